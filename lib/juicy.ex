@@ -4,29 +4,142 @@ defmodule Juicy do
   """
 
   @type ejson :: any
-  @type error :: any
+  @type error :: Juicy.Error.t
+  @type encode_error :: {:invalid_value | :invalid_string, term}
 
   @doc """
   The simplest and most basic parse function.
 
-  Will parse the input binary into ejson. Will return an error tuple if there are any
-  errors in the input.
+  Will parse the input binary into ejson. Will return `{:error, %Juicy.Error{}}`
+  if there are any errors in the input.
 
   This is the fastest parse function, as it does not need to walk a spec tree.
+
+  The following options are supported:
+
+  * `keys: :strings | :atoms | :existing_atoms` - What object keys are
+    parsed into. With `:atoms`, atoms are created as needed, which should
+    not be used with untrusted input since atoms are never garbage
+    collected. `:existing_atoms` only uses atoms that already exist, and
+    fails with reason `:invalid_key` for keys without one. Only
+    ASCII keys can become atoms. Defaults to `:strings`.
+  * `max_depth: pos_integer | :infinity` - How deeply maps and arrays may
+    be nested before parsing fails with reason `:depth_limit`. Defaults
+    to 1024.
   """
-  @spec parse(binary) :: {:ok, ejson} | {:error, error}
-  def parse(binary) do
-    Juicy.Basic.parse(binary)
+  @spec parse(binary, Keyword.t) :: {:ok, ejson} | {:error, error}
+  def parse(binary, opts \\ []) do
+    Juicy.Basic.parse(binary, opts)
   end
 
-  @spec parse_spec(binary, Juicy.Spec.t) :: {:ok, any} | {:error, error}
-  def parse_spec(binary, spec) do
-    Juicy.Basic.parse_spec(binary, spec)
+  @doc """
+  Parses the input binary according to a spec.
+
+  The following options are supported:
+
+  * `max_depth: pos_integer | :infinity` - As for `parse/2`.
+  """
+  @spec parse_spec(binary, Juicy.Spec.t, Keyword.t) :: {:ok, any} | {:error, error}
+  def parse_spec(binary, spec, opts \\ []) do
+    Juicy.Basic.parse_spec(binary, spec, opts)
   end
 
-  @spec parse_stream(Stream.t, Juicy.Spec.t) :: Stream.t
-  def parse_stream(stream, spec) do
-    Juicy.Stream.stream(stream, spec)
+  @doc """
+  Parses a stream of binaries, yielding values as soon as they are complete.
+
+  The stream emits `{:yield, {path, value}}` for every value the spec
+  streams, followed by `:finished`. Errors are emitted as
+  `{:error, %Juicy.Error{}}`.
+
+  The following options are supported:
+
+  * `format: :json | :ndjson | :json_seq | :concatenated` - How the input
+    is made up of values. Defaults to `:json`, a single value.
+
+    With `:ndjson`, the input has one value per line (JSON Lines). Every
+    line is parsed with the spec, and is yielded as
+    `{:yield, {[line_index], value}}`, where `line_index` is the 0-based
+    line it is on. Paths of streamed values are prefixed with it as well.
+    Blank lines are skipped, and lines may end with CRLF. An error only
+    fails the line it is on, parsing continues with the next line.
+
+    `:json_seq` is an RFC 7464 JSON text sequence, where every value is
+    preceded by the record separator `0x1E`. `:concatenated` is values
+    following each other, with optional whitespace between them. Both are
+    yielded like `:ndjson`, but indexed by the number of the record rather
+    than the line. In `:json_seq` an error only fails the record it is in,
+    while there is no way to continue after an error in `:concatenated`.
+  * `offsets: boolean` - Emits `{:record, index, offset}` as every record is
+    started, with the byte offset of its value in the input. Only used with
+    the formats above that have several values. Defaults to `false`.
+  * `path: :list | :pointer` - How paths in yields and errors are given.
+    `:list` is a list of map keys and 0-based array indices, `:pointer` is
+    an RFC 6901 JSON Pointer such as `"/items/0"`, where `~` and `/` in
+    keys are escaped as `~0` and `~1`. Record indices come first in both.
+    Defaults to `:list`.
+  * `max_depth: pos_integer | :infinity` - As for `parse/2`.
+  """
+  @spec parse_stream(Stream.t, Juicy.Spec.t, Keyword.t) :: Stream.t
+  def parse_stream(stream, spec, opts \\ []) do
+    Juicy.Stream.stream(stream, spec, opts)
+  end
+
+  @doc """
+  Parses a stream of binaries, yielding the values picked out by a
+  selector instead of a spec.
+
+  The selector is given with `select:`, in a subset of JSONPath. It starts
+  with `$` for the top level value, followed by any of:
+
+  * `.name` or `['name']` - The value of a key in a map.
+  * `[index]` - The element at a 0-based index in an array.
+  * `.*` or `[*]` - Every value in a map or array.
+  * `..` before any of the above - Applies it at any depth, rather than only
+    to the direct children.
+
+  The stream emits `{:yield, {path, value}}` for every selected value,
+  like `parse_stream/3`, which takes the rest of the options. Values inside
+  of a selected value are not selected again. Everything else is thrown
+  away as it is parsed.
+
+      Juicy.stream(input, select: "$.data.items[*]")
+
+  Raises `ArgumentError` if the selector is invalid.
+  """
+  @spec stream(Stream.t, Keyword.t) :: Stream.t
+  def stream(stream, opts) do
+    {selector, opts} = Keyword.pop(opts, :select)
+    case Juicy.Native.compile_selector(selector) do
+      {:ok, spec} -> parse_stream(stream, spec, opts)
+      :error -> raise ArgumentError, "invalid selector: #{inspect selector}"
+    end
+  end
+
+  @doc """
+  Encodes a term into a JSON binary.
+
+  Maps, lists, binaries, atoms, integers and floats are supported. `nil`,
+  `true` and `false` map to their JSON counterparts, other atoms are written
+  as strings. Structs are encoded as maps without the `__struct__` key.
+
+  By default the output is compact. The following options control layout:
+
+  * `indent: integer` - When not 0, every array element and map pair is
+    written on its own line, indented this many characters per level.
+    Defaults to 0.
+  * `indent_with: :spaces | :tabs` - Character used for indentation.
+    Defaults to `:spaces`.
+  * `newline: :lf | :crlf` - Line ending used when indenting. Defaults to `:lf`.
+  * `colon_space: boolean` - Write a space after the `:` in maps. Defaults
+    to `false`.
+
+  Fails with `{:error, {:invalid_value, term}}` for a term that can't be
+  encoded, and `{:error, {:invalid_string, binary}}` for a binary that
+  is not valid UTF-8.
+  """
+  @spec encode(any, Keyword.t) :: {:ok, binary} | {:error, encode_error}
+  def encode(term, opts \\ []) do
+    Juicy.Encoder.encode(term, opts)
   end
 
   @spec validate_spec(Juicy.Spec.t) :: boolean
@@ -34,4 +147,16 @@ defmodule Juicy do
     Juicy.Native.validate_spec(spec)
   end
 
+  @doc """
+  Reads a spec once, so that it can be reused for many parses.
+
+  The returned compiled spec can be passed to `parse_spec/2` and
+  `parse_stream/2` in place of the spec term. Returns `:error` if the spec is
+  invalid.
+  """
+  @spec compile_spec(Juicy.Spec.t) :: {:ok, Juicy.Spec.compiled} | :error
+  def compile_spec(spec) do
+    Juicy.Native.compile_spec(spec)
+  end
+
 end
@@ -1,8 +1,8 @@
 defmodule Juicy.Basic do
   @moduledoc false
 
-  def parse(binary) do
-    handle_parse_return(binary, Juicy.Native.parse_init(binary))
+  def parse(binary, opts) do
+    handle_parse_return(binary, Juicy.Native.parse_init(binary, opts))
   end
 
   defp handle_parse_return(binary, {:iter, stack, res}) do
@@ -10,8 +10,8 @@ defmodule Juicy.Basic do
   end
   defp handle_parse_return(_, ret), do: ret
 
-  def parse_spec(binary, spec) do
-    {:ok, state} = Juicy.Native.spec_parse_init(binary, spec)
+  def parse_spec(binary, spec, opts) do
+    {:ok, state} = Juicy.Native.spec_parse_init(binary, spec, opts)
     handle_parse_spec_return(Juicy.Native.spec_parse_iter(state))
   end
 
@@ -0,0 +1,56 @@
+defmodule Juicy.Error do
+  @moduledoc """
+  Returned as `{:error, %Juicy.Error{}}` when parsing fails, by all of the
+  parse functions.
+
+  `reason` is one of:
+
+  * `:unexpected_eof` - The input ended in the middle of a value.
+  * `:unexpected_character` - The input is not valid JSON at `position`.
+  * `:invalid_escape` - A string contains an invalid escape sequence.
+  * `:invalid_utf8` - A string is not valid UTF-8.
+  * `:trailing_data` - There is more than whitespace after the value.
+  * `:depth_limit` - Maps and arrays are nested deeper than `detail`.
+  * `:invalid_key` - A key could not be made into an atom, `detail` is the key.
+  * `:invalid_number` - With `detail` `:out_of_range`, a number is too large
+    to be represented.
+  * `:internal_error` - The parser ended up in a state it should not be able
+    to. Please report these.
+
+  Parsing with a spec can also fail with:
+
+  * `:spec_mismatch` - The input doesn't match the spec, `detail` is
+    `{expected, got}`.
+  * `:unknown_key` - A key not allowed by `unknown_keys: :error`.
+  * `:unknown_tag` - A tag without a variant, `detail` is the tag.
+  * `:missing_tag` - A tagged value without its tag.
+  * `:invalid_enum` - A string not in the enum, `detail` is the string.
+  * `:tuple_length` - `detail` is `{expected, got}`.
+  * `:string_too_long` - `detail` is the maximum length.
+  * `:string_too_short` - `detail` is `{min, length}`.
+  * `:invalid_number` - `detail` is the constraint that failed.
+
+  `position` is the byte offset in the input, `line` and `column` are
+  1-based and `column` counts codepoints. `path` is the list of keys and
+  indices leading to the value that failed, or `nil` when it is not known.
+  Streams parsed with `path: :pointer` give it as a JSON Pointer instead.
+  `excerpt` is the input around the error, on the same line.
+  """
+
+  defexception [:reason, :detail, :position, :path, :line, :column, :excerpt]
+
+  @type t :: %__MODULE__{
+    reason: atom,
+    detail: any,
+    position: non_neg_integer,
+    path: [binary | non_neg_integer] | binary | nil,
+    line: pos_integer,
+    column: pos_integer,
+    excerpt: binary,
+  }
+
+  def message(%__MODULE__{reason: reason, line: line, column: column}) do
+    "#{reason} at line #{line}, column #{column}"
+  end
+
+end
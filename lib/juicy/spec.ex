@@ -1,5 +1,8 @@
 defmodule Juicy.Spec do
 
-  @type t :: any
+  @type t :: any | compiled
+
+  @typedoc "A spec compiled with `Juicy.compile_spec/1`."
+  @type compiled :: reference
 
 end
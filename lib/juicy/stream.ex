@@ -1,9 +1,10 @@
 defmodule Juicy.Stream do
   @moduledoc false
 
-  defstruct reader: nil, spec: nil, binaries: nil, parser: nil, output_queue: [], state: {:read_input, :parsing_not_done, nil}
+  defstruct reader: nil, spec: nil, opts: [], binaries: nil, parser: nil, output_queue: [],
+            input_done: false, state: {:read_input, :parsing_not_done, nil}
 
-  def stream(input, spec) do
+  def stream(input, spec, opts \\ []) do
     reader = input
     |> Stream.transform(0, fn(elem, pos) -> {[{pos, elem}], pos+byte_size(elem)} end)
     |> stream_take_init
@@ -11,6 +12,7 @@ defmodule Juicy.Stream do
     %__MODULE__{
       reader: reader,
       spec: spec,
+      opts: opts,
     }
   end
 
@@ -38,7 +40,7 @@ defimpl Enumerable, for: Juicy.Stream do
   end
 
   def reduce(js = %Juicy.Stream{}, acc, fun) do
-    {:ok, parser} = Juicy.Native.stream_parse_init(js.spec)
+    {:ok, parser} = Juicy.Native.stream_parse_init(js.spec, js.opts)
     js = %Juicy.Stream{ js |
             parser: parser,
             binaries: [],
@@ -47,7 +49,9 @@ defimpl Enumerable, for: Juicy.Stream do
   end
 
   defp do_reduce(js, {:halt, acc}, fun) do
-    Juicy.Stream.stream_take_halt(js.reader)
+    unless js.input_done do
+      Juicy.Stream.stream_take_halt(js.reader)
+    end
     {:halted, acc}
   end
   defp do_reduce(js, {:suspend, acc}, fun) do
@@ -68,15 +72,18 @@ defimpl Enumerable, for: Juicy.Stream do
                     }
               {:loop, js}
             {:halted, _} ->
+              # Lets the parser finish the value, or report where the input
+              # ended early.
               js = %{js |
-                     state: {:emit_items, :parsing_done, nil},
-                     output_queue: [{:error, :early_eoi}],
+                     input_done: true,
+                     state: {:parse, :parsing_not_done, nil},
                     }
               {:loop, js}
           end
 
         {:parse, :parsing_not_done, _} ->
-          {status, yields, binaries, state} = Juicy.Native.stream_parse_iter(js.binaries, js.parser)
+          {status, yields, binaries, state} =
+            Juicy.Native.stream_parse_iter(js.binaries, js.parser, js.input_done)
           js = %{js | output_queue: yields, parser: state, binaries: binaries}
           case status do
             :finished -> {:loop, %{js | state: {:emit_items, :parsing_done, nil}}}
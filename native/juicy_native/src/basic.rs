@@ -1,21 +1,100 @@
 use iterative_json_parser::{Parser, Source, PeekResult, Sink, Range, Pos, NumberData, ParseError,
                             Unexpected, Bailable, Position, StringPosition};
 
-use rustler::{NifEnv, NifTerm, NifResult, NifEncoder};
+use rustler::{NifEnv, NifTerm, NifResult, NifEncoder, NifError};
 use rustler::resource::ResourceArc;
+use rustler::types::atom::NifAtom;
 use rustler::types::binary::NifBinary;
+use rustler::types::list::NifListIterator;
 use rustler::types::map::map_new;
 use rustler::types::binary::OwnedNifBinary;
 
-use ::strings::BuildString;
+use ::strings::{BuildString, bytes_to_term};
 use ::numbers::number_data_to_term;
 use ::input_provider::InputProvider;
 use ::input_provider::single::SingleBinaryProvider;
+use ::location::LineCounter;
+use ::errors::{ParseFailure, DEFAULT_MAX_DEPTH, read_max_depth, classify_unexpected, find_trailing,
+               internal_error};
 
 use std::io::Write;
 use std::sync::Mutex;
 use std::ops::DerefMut;
 
+mod atoms {
+    rustler_atoms! {
+        atom keys;
+        atom atoms;
+        atom existing_atoms;
+        atom strings;
+        atom max_depth;
+    }
+}
+
+/// How object keys are turned into terms.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyMode {
+    Strings,
+    /// Keys become atoms, which are created when needed.
+    Atoms,
+    /// Keys become atoms that already exist. Keys without one fail the
+    /// parse, so input can not fill up the atom table.
+    ExistingAtoms,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    pub keys: KeyMode,
+    /// Maximum nesting of maps and arrays.
+    pub max_depth: usize,
+}
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            keys: KeyMode::Strings,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+fn read_opts<'a>(term: NifTerm<'a>) -> NifResult<ParseOptions> {
+    let iterator: NifListIterator = term.decode()?;
+    let mut opts = ParseOptions::default();
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::keys() == key {
+            opts.keys = if atoms::strings() == value {
+                KeyMode::Strings
+            } else if atoms::atoms() == value {
+                KeyMode::Atoms
+            } else if atoms::existing_atoms() == value {
+                KeyMode::ExistingAtoms
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::max_depth() == key {
+            opts.max_depth = read_max_depth(value)?;
+        } else {
+            return Err(NifError::BadArg);
+        }
+    }
+    Ok(opts)
+}
+
+/// Atoms are made from latin-1, so only ASCII keys can be converted without
+/// changing them. Atoms are also limited to 255 characters.
+fn key_to_atom<'a>(env: NifEnv<'a>, key: &[u8], existing_only: bool) -> Option<NifAtom> {
+    if key.len() > 255 || !key.is_ascii() {
+        return None;
+    }
+    if existing_only {
+        NifAtom::try_from_bytes(env, key).ok().and_then(|atom| atom)
+    } else {
+        NifAtom::from_bytes(env, key).ok()
+    }
+}
+
 struct BasicSS<'a, 'b> {
     env: NifEnv<'a>,
 
@@ -24,8 +103,40 @@ struct BasicSS<'a, 'b> {
     position: usize,
     next_reschedule: usize,
 
+    keys: KeyMode,
+    max_depth: usize,
+
     out_stack: Vec<NifTerm<'a>>,
     current_string: &'b mut BuildString,
+    /// Number of maps and arrays we are in.
+    depth: &'b mut usize,
+    /// Set when the sink fails the parse. The path is filled in when the
+    /// error is reported.
+    error: Option<ParseFailure<'a>>,
+}
+
+impl<'a, 'b> BasicSS<'a, 'b> {
+
+    fn fail(&mut self, reason: NifAtom, detail: NifTerm<'a>) {
+        self.error = Some(ParseFailure {
+            reason: reason,
+            position: self.position,
+            path: ::atoms::nil().encode(self.env),
+            detail: detail,
+        });
+        // Makes `peek_char` bail, for the sink functions that can't fail by
+        // themselves.
+        self.next_reschedule = self.position;
+    }
+
+    fn enter_nested(&mut self) {
+        *self.depth += 1;
+        if *self.depth > self.max_depth {
+            let detail = (self.max_depth as u64).encode(self.env);
+            self.fail(::atoms::depth_limit(), detail);
+        }
+    }
+
 }
 
 impl<'a, 'b> Bailable for BasicSS<'a, 'b> {
@@ -60,9 +171,11 @@ impl<'a, 'b> Source for BasicSS<'a, 'b> {
 
 impl<'a, 'b> Sink for BasicSS<'a, 'b> {
     fn push_map(&mut self, _pos: Position) {
+        self.enter_nested();
         self.out_stack.push(map_new(self.env));
     }
     fn push_array(&mut self, _pos: Position) {
+        self.enter_nested();
         let arr: Vec<NifTerm> = Vec::new();
         self.out_stack.push(arr.encode(self.env));
     }
@@ -70,8 +183,17 @@ impl<'a, 'b> Sink for BasicSS<'a, 'b> {
         let term = number_data_to_term(self.env, num, |r, b| {
             self.input.push_range(r, b);
         });
-        self.out_stack.push(term);
-        Ok(())
+        match term {
+            Some(term) => {
+                self.out_stack.push(term);
+                Ok(())
+            }
+            None => {
+                let detail = ::atoms::out_of_range().encode(self.env);
+                self.fail(::atoms::invalid_number(), detail);
+                Err(())
+            }
+        }
     }
     fn push_bool(&mut self, _pos: Position, val: bool) -> Result<(), Self::Bail> {
         self.out_stack.push(val.encode(self.env));
@@ -103,11 +225,41 @@ impl<'a, 'b> Sink for BasicSS<'a, 'b> {
             input.push_range(r, b);
         });
     }
-    fn finalize_string(&mut self, _pos: StringPosition) -> Result<(), Self::Bail> {
+    fn finalize_string(&mut self, pos: StringPosition) -> Result<(), Self::Bail> {
+        if let Err(invalid_pos) = self.current_string.check_utf8(&self.input) {
+            if let Some(invalid_pos) = invalid_pos {
+                self.position = invalid_pos;
+            }
+            let nil = ::atoms::nil().encode(self.env);
+            self.fail(::atoms::invalid_utf8(), nil);
+            return Err(());
+        }
+
+        let existing_only = match (pos, self.keys) {
+            (StringPosition::MapKey, KeyMode::Atoms) => Some(false),
+            (StringPosition::MapKey, KeyMode::ExistingAtoms) => Some(true),
+            _ => None,
+        };
+        if let Some(existing_only) = existing_only {
+            let key = self.current_string.to_vec(&self.input);
+            *self.current_string = BuildString::None;
+            return match key_to_atom(self.env, &key, existing_only) {
+                Some(atom) => {
+                    self.out_stack.push(atom.encode(self.env));
+                    Ok(())
+                }
+                None => {
+                    let key_term = bytes_to_term(self.env, &key);
+                    self.fail(::atoms::invalid_key(), key_term);
+                    Err(())
+                }
+            };
+        }
+
         let string_term = match *self.current_string {
             BuildString::None => "".encode(self.env),
             BuildString::Range(range) => self.input.range_to_term(self.env, range),
-            BuildString::Owned(ref buf) => {
+            BuildString::Owned(ref buf, _) => {
                 let mut bin = OwnedNifBinary::new(buf.len()).unwrap();
                 bin.as_mut_slice().write(buf).unwrap();
                 bin.release(self.env).encode(self.env)
@@ -119,9 +271,11 @@ impl<'a, 'b> Sink for BasicSS<'a, 'b> {
     }
 
     fn finalize_map(&mut self, _pos: Position) -> Result<(), Self::Bail> {
+        *self.depth -= 1;
         Ok(())
     }
     fn finalize_array(&mut self, _pos: Position) -> Result<(), Self::Bail> {
+        *self.depth -= 1;
         let term = self.out_stack.pop().unwrap();
         self.out_stack.push(term.list_reverse().ok().unwrap());
         Ok(())
@@ -139,21 +293,73 @@ impl<'a, 'b> Sink for BasicSS<'a, 'b> {
     }
 }
 
+/// The path to the value being parsed, worked out from the maps and arrays
+/// being built on the stack.
+fn stack_path<'a>(env: NifEnv<'a>, stack: &[NifTerm<'a>]) -> NifTerm<'a> {
+    let mut path: Vec<NifTerm<'a>> = Vec::new();
+    let mut idx = 0;
+    while idx < stack.len() {
+        let term = stack[idx];
+        if term.map_size().is_ok() {
+            // Followed by the key of the pair being parsed, if we are past
+            // it.
+            if let Some(&key) = stack.get(idx + 1) {
+                path.push(key);
+            }
+            idx += 2;
+        } else if let Ok(length) = term.list_length() {
+            path.push((length as u64).encode(env));
+            idx += 1;
+        } else {
+            break;
+        }
+    }
+    path.encode(env)
+}
+
+fn format_failure<'a>(env: NifEnv<'a>,
+                      input: &SingleBinaryProvider<'a>,
+                      stack: &[NifTerm<'a>],
+                      mut failure: ParseFailure<'a>)
+                      -> NifTerm<'a> {
+    failure.path = stack_path(env, stack);
+    failure.to_term(env, |pos| input.byte(pos), &LineCounter::new())
+}
+
 fn format_unexpected<'a>(env: NifEnv<'a>,
-                         parser: &Parser,
+                         input: &SingleBinaryProvider<'a>,
+                         stack: &[NifTerm<'a>],
                          pos: Pos,
                          reason: Unexpected)
                          -> NifTerm<'a> {
-    let parser_state = format!("{:?}", parser).encode(env);
-    let position = pos.0 as u64;
-    let explaination = reason.explain().encode(env);
-    (::atoms::error(), (::atoms::unexpected(), position, explaination, parser_state)).encode(env)
+    let failure = ParseFailure {
+        reason: classify_unexpected(|pos| input.byte(pos), pos.0, &reason),
+        position: pos.0,
+        path: ::atoms::nil().encode(env),
+        detail: ::atoms::nil().encode(env),
+    };
+    format_failure(env, input, stack, failure)
+}
+
+/// Fails if there is anything but whitespace after the value.
+fn check_trailing<'a>(env: NifEnv<'a>, input: &SingleBinaryProvider<'a>, position: usize)
+                      -> Option<ParseFailure<'a>> {
+    find_trailing(|pos| input.byte(pos), position).map(|position| {
+        ParseFailure {
+            reason: ::atoms::trailing_data(),
+            position: position,
+            path: ::atoms::nil().encode(env),
+            detail: ::atoms::nil().encode(env),
+        }
+    })
 }
 
 pub struct IterState {
     parser: Parser,
+    opts: ParseOptions,
     source_pos: usize,
     sink_string_state: BuildString,
+    depth: usize,
 }
 pub struct IterStateWrapper(Mutex<IterState>);
 
@@ -167,15 +373,26 @@ fn parse_inner<'a>(env: NifEnv<'a>,
         input: SingleBinaryProvider::new(input),
         position: iter_state.source_pos,
         next_reschedule: iter_state.source_pos + 40_000,
+        keys: iter_state.opts.keys,
+        max_depth: iter_state.opts.max_depth,
         out_stack: stack,
         current_string: &mut iter_state.sink_string_state,
+        depth: &mut iter_state.depth,
+        error: None,
     };
 
     let result = iter_state.parser.run(&mut ss);
     iter_state.source_pos = ss.position;
 
+    if let Some(failure) = ss.error {
+        return Ok(format_failure(env, &ss.input, &ss.out_stack, failure));
+    }
+
     match result {
         Ok(()) => {
+            if let Some(failure) = check_trailing(env, &ss.input, ss.position) {
+                return Ok(format_failure(env, &ss.input, &[], failure));
+            }
             let term = ss.out_stack.pop().unwrap();
             Ok((::atoms::ok(), term).encode(env))
         }
@@ -183,19 +400,25 @@ fn parse_inner<'a>(env: NifEnv<'a>,
             Err(ss.out_stack)
         }
         Err(ParseError::Unexpected(pos, reason)) => {
-            Ok(format_unexpected(env, &iter_state.parser, pos, reason))
+            Ok(format_unexpected(env, &ss.input, &ss.out_stack, pos, reason))
+        }
+        Err(_) => {
+            let failure = internal_error(env, ss.position);
+            Ok(format_failure(env, &ss.input, &ss.out_stack, failure))
         }
-        err => panic!("{:?}", err),
     }
 }
 
 pub fn parse<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
     let input: NifBinary = args[0].decode()?;
+    let opts = read_opts(args[1])?;
 
     let mut iter_state = IterState {
         parser: Parser::new(),
+        opts: opts,
         source_pos: 0,
         sink_string_state: BuildString::None,
+        depth: 0,
     };
 
     match parse_inner(env, input, vec![], &mut iter_state) {
@@ -211,7 +434,12 @@ pub fn parse_iter<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTer
     let input: NifBinary = args[0].decode()?;
     let stack: Vec<NifTerm<'a>> = args[1].decode()?;
     let resource: ResourceArc<IterStateWrapper> = args[2].decode()?;
-    let mut resource_inner_guard = resource.0.lock().unwrap();
+    // Only poisoned if a previous call panicked, the state can't be trusted
+    // after that.
+    let mut resource_inner_guard = match resource.0.lock() {
+        Ok(guard) => guard,
+        Err(_) => return Err(NifError::BadArg),
+    };
     let mut resource_inner = resource_inner_guard.deref_mut();
 
     match parse_inner(env, input, stack, resource_inner) {
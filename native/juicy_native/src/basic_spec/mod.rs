@@ -1,15 +1,19 @@
 use iterative_json_parser::{Parser, Pos, ParseError, Unexpected};
 
-use rustler::{NifEnv, NifTerm, NifResult, NifEncoder};
+use rustler::{NifEnv, NifTerm, NifResult, NifEncoder, NifError};
 use rustler::resource::ResourceArc;
 use rustler::types::binary::NifBinary;
+use rustler::types::list::NifListIterator;
 
 use ::strings::BuildString;
 
-use ::tree_spec::spec_from_term;
-use ::tree_spec::SpecWalker;
+use ::tree_spec::read_spec;
 
+use ::input_provider::InputProvider;
 use ::input_provider::single::SingleBinaryProvider;
+use ::location::LineCounter;
+use ::errors::{ParseFailure, DEFAULT_MAX_DEPTH, read_max_depth, classify_unexpected, find_trailing,
+               internal_error};
 
 use ::path_tracker::PathTracker;
 
@@ -19,10 +23,62 @@ use std::ops::DerefMut;
 mod source_sink;
 use self::source_sink::{StreamingSS, SSState};
 
-fn format_unexpected<'a>(env: NifEnv<'a>, pos: Pos, reason: Unexpected) -> NifTerm<'a> {
-    let position = pos.0 as u64;
-    let explaination = reason.explain().encode(env);
-    (::atoms::error(), (::atoms::unexpected(), position, explaination)).encode(env)
+mod atoms {
+    rustler_atoms! {
+        atom max_depth;
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum BailType {
+    Reschedule,
+    /// The sink failed. The error term is stored in the source sink.
+    Error,
+}
+
+/// Reads the options, and returns the maximum depth, the only one there is.
+fn read_opts<'a>(term: NifTerm<'a>) -> NifResult<usize> {
+    let iterator: NifListIterator = term.decode()?;
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::max_depth() == key {
+            max_depth = read_max_depth(value)?;
+        } else {
+            return Err(NifError::BadArg);
+        }
+    }
+    Ok(max_depth)
+}
+
+fn format_unexpected<'a>(env: NifEnv<'a>,
+                         input: &SingleBinaryProvider<'a>,
+                         ss_state: &SSState,
+                         pos: &Pos,
+                         reason: &Unexpected)
+                         -> NifTerm<'a> {
+    let failure = ParseFailure {
+        reason: classify_unexpected(|pos| input.byte(pos), pos.0, reason),
+        position: pos.0,
+        path: ss_state.path_tracker.path.encode(env),
+        detail: ::atoms::nil().encode(env),
+    };
+    failure.to_term(env, |pos| input.byte(pos), &LineCounter::new())
+}
+
+/// Fails if there is anything but whitespace after the value.
+fn check_trailing<'a>(env: NifEnv<'a>, input: &SingleBinaryProvider<'a>, ss_state: &SSState)
+                      -> Option<NifTerm<'a>> {
+    find_trailing(|pos| input.byte(pos), ss_state.position).map(|position| {
+        let failure = ParseFailure {
+            reason: ::atoms::trailing_data(),
+            position: position,
+            path: ss_state.path_tracker.path.encode(env),
+            detail: ::atoms::nil().encode(env),
+        };
+        failure.to_term(env, |pos| input.byte(pos), &LineCounter::new())
+    })
 }
 
 pub struct BasicSpecIterState {
@@ -33,17 +89,21 @@ pub struct BasicSpecIterStateWrapper(Mutex<BasicSpecIterState>);
 
 pub fn parse_init<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
     let binary: NifBinary = args[0].decode()?;
-    let spec = spec_from_term(args[1])?;
+    let spec = read_spec(args[1])?;
+    let max_depth = read_opts(args[2])?;
 
     let ss_state = SSState {
-        path_tracker: PathTracker {
-            path: Vec::new(),
-            walker: SpecWalker::new(spec),
-        },
+        path_tracker: PathTracker::new(spec),
 
         position: 0,
         first_needed: 0,
         current_string: BuildString::None,
+        tag_lookahead: false,
+        tag_scan: None,
+        max_depth: max_depth,
+        string_position: None,
+        string_length: 0,
+        string_skipped: false,
     };
 
     let iter_state = BasicSpecIterState {
@@ -62,8 +122,13 @@ pub fn parse_iter<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTer
                                     Vec<NifTerm<'a>>,
                                     ResourceArc<BasicSpecIterStateWrapper>) = args[0].decode()?;
 
-    let (res, mut out_stack) = {
-        let mut resource_inner_guard = resource.0.lock().unwrap();
+    let (res, mut out_stack, error) = {
+        // Only poisoned if a previous call panicked, the state can't be
+        // trusted after that.
+        let mut resource_inner_guard = match resource.0.lock() {
+            Ok(guard) => guard,
+            Err(_) => return Err(NifError::BadArg),
+        };
         let mut iter_state = resource_inner_guard.deref_mut();
 
         let mut ss = StreamingSS {
@@ -72,25 +137,43 @@ pub fn parse_iter<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTer
             next_reschedule: iter_state.ss_state.position + 40_000,
             out_stack: stack,
             state: &mut iter_state.ss_state,
+            error: None,
         };
 
         let res = iter_state.parser.run(&mut ss);
-        (res, ss.out_stack)
+        let (out_stack, mut error) = (ss.out_stack, ss.error);
+
+        if error.is_none() {
+            let input = SingleBinaryProvider::new(binary);
+            error = match res {
+                Ok(()) => check_trailing(env, &input, &iter_state.ss_state),
+                Err(ParseError::Unexpected(ref pos, ref reason)) =>
+                    Some(format_unexpected(env, &input, &iter_state.ss_state, pos, reason)),
+                Err(ParseError::SourceBail(BailType::Reschedule)) => None,
+                Err(_) => {
+                    let failure = internal_error(env, iter_state.ss_state.position);
+                    Some(failure.to_term(env, |pos| input.byte(pos), &LineCounter::new()))
+                }
+            };
+        }
+
+        (res, out_stack, error)
     };
 
+    if let Some(error) = error {
+        return Ok(error);
+    }
+
     match res {
         Ok(()) => {
             let result = out_stack.pop().unwrap();
             Ok((::atoms::ok(), result).encode(env))
         }
-        Err(ParseError::SourceBail(())) => {
+        // Only rescheduling is left, everything else was reported as an
+        // error above.
+        Err(_) => {
             let state = (binary, out_stack, resource).encode(env);
             Ok((::atoms::iter(), state).encode(env))
         }
-        Err(ParseError::Unexpected(pos, reason)) => {
-            let error = format_unexpected(env, pos, reason);
-            Ok((::atoms::error(), error).encode(env))
-        }
-        Err(_) => panic!("TODO: Add proper error"),
     }
 }
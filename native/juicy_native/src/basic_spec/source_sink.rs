@@ -1,13 +1,11 @@
-use std::io::Write;
+use super::BailType;
 
-use ::strings::BuildString;
-use ::numbers::number_data_to_term;
+use ::strings::{BuildString, bytes_to_term};
 
-use ::tree_spec::ValueType;
+use ::tree_spec::{ValueType, PathPosition, LengthUnit};
 
 use rustler::{NifEnv, NifTerm, NifEncoder};
 use rustler::types::map::map_new;
-use rustler::types::binary::OwnedNifBinary;
 
 use ::iterative_json_parser::{Bailable, Source, Sink, Pos, PeekResult, Position, NumberData,
                               StringPosition};
@@ -17,6 +15,10 @@ use ::input_provider::InputProvider;
 use ::input_provider::single::SingleBinaryProvider;
 
 use ::path_tracker::PathTracker;
+use ::location::LineCounter;
+use ::errors::ParseFailure;
+use ::lookahead::{KeyScan, LookaheadResult};
+use ::spec_sink::{self, SpecFailure};
 
 pub struct StreamingSS<'a, 'b>
     where 'a: 'b
@@ -26,6 +28,7 @@ pub struct StreamingSS<'a, 'b>
     pub next_reschedule: usize,
     pub out_stack: Vec<NifTerm<'a>>,
     pub state: &'b mut SSState,
+    pub error: Option<NifTerm<'a>>,
 }
 
 pub struct SSState {
@@ -34,10 +37,25 @@ pub struct SSState {
     pub position: usize,
     pub first_needed: usize,
     pub current_string: BuildString,
+    /// Set when we have entered a tagged map, and need to look for the tag
+    /// before the pairs in it can be parsed.
+    pub tag_lookahead: bool,
+    /// How far the look for the tag got before it was suspended.
+    pub tag_scan: Option<KeyScan>,
+    /// Maximum nesting of maps and arrays.
+    pub max_depth: usize,
+    /// Where the string value being built is in the spec.
+    pub string_position: Option<PathPosition>,
+    /// Length of the string value being built so far, only counted when
+    /// its node has length constraints.
+    pub string_length: usize,
+    /// Whether the string being parsed is left out of the output, in which
+    /// case it is not built.
+    pub string_skipped: bool,
 }
 
 impl<'a, 'b> Bailable for StreamingSS<'a, 'b> {
-    type Bail = ();
+    type Bail = BailType;
 }
 
 impl<'a, 'b> Source for StreamingSS<'a, 'b> {
@@ -47,13 +65,22 @@ impl<'a, 'b> Source for StreamingSS<'a, 'b> {
     fn skip(&mut self, num: usize) {
         self.state.position += num
     }
-    fn peek_char(&mut self) -> PeekResult<()> {
+    fn peek_char(&mut self) -> PeekResult<BailType> {
+        // Set by sink functions that can't fail by themselves.
+        if self.error.is_some() {
+            return PeekResult::Bail(BailType::Error);
+        }
+        if self.state.tag_lookahead {
+            if let Err(bail) = self.lookahead_tag() {
+                return PeekResult::Bail(bail);
+            }
+        }
         if self.state.position == self.next_reschedule {
-            PeekResult::Bail(())
+            PeekResult::Bail(BailType::Reschedule)
         } else {
             match self.input.byte(self.state.position) {
                 Some(byte) => PeekResult::Ok(byte),
-                None => unreachable!(),
+                None => PeekResult::Eof,
             }
         }
     }
@@ -62,44 +89,165 @@ impl<'a, 'b> Source for StreamingSS<'a, 'b> {
     }
 }
 
+impl<'a, 'b> StreamingSS<'a, 'b> {
+
+    fn fail<T>(&mut self, failure: SpecFailure<'a>) -> Result<T, BailType> {
+        let failure = ParseFailure {
+            reason: failure.reason,
+            position: failure.position.unwrap_or(self.state.position),
+            path: self.state.path_tracker.path[..failure.path_len].encode(self.env),
+            detail: failure.detail,
+        };
+        let input = &self.input;
+        self.error = Some(failure.to_term(self.env, |pos| input.byte(pos), &LineCounter::new()));
+        Err(BailType::Error)
+    }
+
+    /// Fails if we are nested deeper than allowed. Called right after
+    /// entering a map or array.
+    fn check_depth(&mut self) {
+        if self.state.path_tracker.depth() > self.state.max_depth && self.error.is_none() {
+            let detail = (self.state.max_depth as u64).encode(self.env);
+            let failure = SpecFailure::new(&self.state.path_tracker, ::atoms::depth_limit(), detail);
+            // Can't fail from here, `peek_char` picks up the error.
+            self.fail::<()>(failure).ok();
+        }
+    }
+
+    /// Adds to the length of the string value being built. Fails as soon
+    /// as it goes past the maximum length, before the rest of the string is
+    /// built.
+    fn add_string_length(&mut self, length: usize) {
+        self.state.string_length += length;
+        let checked = spec_sink::check_max_length(self.env, &self.state.path_tracker,
+                                                  self.state.string_position, self.state.string_length);
+        if let Err(failure) = checked {
+            if self.error.is_none() {
+                // Can't fail from here, `peek_char` picks up the error.
+                self.fail::<()>(failure).ok();
+            }
+        }
+    }
+
+    /// Decides what node the tagged map we just entered should be parsed
+    /// as, by looking ahead in the input for the tag.
+    fn lookahead_tag(&mut self) -> Result<(), BailType> {
+        self.state.tag_lookahead = false;
+        let position = self.state.position;
+        let mut scan = self.state.tag_scan.take().unwrap_or_else(|| KeyScan::new(position));
+        // Counts against the same budget as parsing does.
+        let budget = self.next_reschedule.saturating_sub(position);
+
+        let result = {
+            let input = &self.input;
+            spec_sink::scan_tag(&self.state.path_tracker, &mut scan, |pos| input.byte(pos), budget)
+        };
+
+        let tag = match result {
+            Some(LookaheadResult::Found(tag)) => tag,
+            Some(LookaheadResult::Missing) => None,
+            Some(LookaheadResult::Suspended) => {
+                self.state.tag_lookahead = true;
+                self.state.tag_scan = Some(scan);
+                return Err(BailType::Reschedule);
+            }
+            // The parser will run into the error and report it.
+            Some(LookaheadResult::Incomplete) | Some(LookaheadResult::Invalid) => return Ok(()),
+            None => {
+                let failure = SpecFailure::internal(self.env, &self.state.path_tracker);
+                return self.fail(failure);
+            }
+        };
+
+        match spec_sink::resolve_map_tag(self.env, &self.state.path_tracker, tag) {
+            Ok(resolved_id) => {
+                if self.state.path_tracker.replace_current(resolved_id, ValueType::Object) {
+                    self.out_stack.pop().unwrap();
+                }
+                Ok(())
+            }
+            Err(failure) => self.fail(failure),
+        }
+    }
+
+}
+
 impl<'a, 'b> Sink for StreamingSS<'a, 'b> {
     fn push_map(&mut self, pos: Position) {
-        self.out_stack.push(map_new(self.env));
+        let path_pos = self.state.path_tracker.enter_map(pos);
+        if let Err(failure) = spec_sink::check_match(self.env, &self.state.path_tracker, &path_pos,
+                                                     ValueType::Object) {
+            self.fail::<()>(failure).ok();
+        }
+        self.check_depth();
+        if !self.state.path_tracker.skipping() {
+            self.out_stack.push(map_new(self.env));
+            self.state.tag_lookahead = spec_sink::is_tagged_map(&self.state.path_tracker, path_pos.current);
+        }
 
-        self.state.path_tracker.enter_map(pos);
         self.state.first_needed = self.state.position;
     }
     fn push_array(&mut self, pos: Position) {
-        let arr: Vec<NifTerm> = Vec::new();
-        self.out_stack.push(arr.encode(self.env));
+        let path_pos = self.state.path_tracker.enter_array(pos);
+        if let Err(failure) = spec_sink::check_match(self.env, &self.state.path_tracker, &path_pos,
+                                                     ValueType::Array) {
+            self.fail::<()>(failure).ok();
+        }
+        self.check_depth();
+        if !self.state.path_tracker.skipping() {
+            let arr: Vec<NifTerm> = Vec::new();
+            self.out_stack.push(arr.encode(self.env));
+        }
 
-        self.state.path_tracker.enter_array(pos);
         self.state.first_needed = self.state.position;
     }
     fn push_number(&mut self, pos: Position, num: NumberData) -> Result<(), Self::Bail> {
-        let term = number_data_to_term(self.env, num, |r, b| self.input.push_range(r, b));
-        self.out_stack.push(term);
-
         let curr_node = self.state.path_tracker.visit_terminal(pos, ValueType::Number);
-        //self.do_stream(curr_node)?;
+        let value = spec_sink::check_match(self.env, &self.state.path_tracker, &curr_node, ValueType::Number)
+            .and_then(|()| {
+                if self.state.path_tracker.skipping() {
+                    return Ok(None);
+                }
+                spec_sink::number_value(self.env, &self.state.path_tracker, curr_node.current, num,
+                                        &self.input).map(Some)
+            });
+        match value {
+            Ok(Some(term)) => self.out_stack.push(term),
+            Ok(None) => (),
+            Err(failure) => return self.fail(failure),
+        }
 
         self.state.first_needed = self.state.position;
         Ok(())
     }
     fn push_bool(&mut self, pos: Position, val: bool) -> Result<(), Self::Bail> {
-        self.out_stack.push(val.encode(self.env));
-
         let curr_node = self.state.path_tracker.visit_terminal(pos, ValueType::Boolean);
-        //self.do_stream(curr_node)?;
+        if let Err(failure) = spec_sink::check_match(self.env, &self.state.path_tracker, &curr_node,
+                                                     ValueType::Boolean) {
+            return self.fail(failure);
+        }
+
+        if !self.state.path_tracker.skipping() {
+            let term = spec_sink::static_value(self.env, &self.state.path_tracker, curr_node.current)
+                .unwrap_or_else(|| val.encode(self.env));
+            self.out_stack.push(term);
+        }
 
         self.state.first_needed = self.state.position;
         Ok(())
     }
     fn push_null(&mut self, pos: Position) -> Result<(), Self::Bail> {
-        self.out_stack.push(::atoms::nil().encode(self.env));
-
         let curr_node = self.state.path_tracker.visit_terminal(pos, ValueType::Null);
-        //self.do_stream(curr_node)?;
+        if let Err(failure) = spec_sink::check_match(self.env, &self.state.path_tracker, &curr_node,
+                                                     ValueType::Null) {
+            return self.fail(failure);
+        }
+
+        if !self.state.path_tracker.skipping() {
+            let term = spec_sink::static_value(self.env, &self.state.path_tracker, curr_node.current)
+                .unwrap_or_else(|| ::atoms::nil().encode(self.env));
+            self.out_stack.push(term);
+        }
 
         self.state.first_needed = self.state.position;
         Ok(())
@@ -107,19 +255,63 @@ impl<'a, 'b> Sink for StreamingSS<'a, 'b> {
 
     fn start_string(&mut self, pos: StringPosition) {
         self.state.current_string = match pos {
-            StringPosition::MapKey => BuildString::new_owned(),
-            _ => BuildString::new(),
+            StringPosition::MapKey => {
+                // Whether the pair is dropped is only known once the key is
+                // complete, so it is always built unless the map is skipped.
+                self.state.string_position = None;
+                self.state.string_skipped = self.state.path_tracker.in_skipped_subtree();
+                BuildString::new_owned()
+            }
+            _ => {
+                // Visited here rather than when the string is finalized, so
+                // that length constraints can be checked while it is built.
+                let curr_node = self.state.path_tracker.visit_terminal(pos.to_position(), ValueType::String);
+                if let Err(failure) = spec_sink::check_match(self.env, &self.state.path_tracker, &curr_node,
+                                                             ValueType::String) {
+                    self.fail::<()>(failure).ok();
+                }
+                self.state.string_position = Some(curr_node);
+                self.state.string_length = 0;
+                self.state.string_skipped = self.state.path_tracker.skipping();
+                BuildString::new()
+            }
         };
     }
     fn append_string_range(&mut self, range: PRange) {
+        if self.state.string_skipped {
+            return;
+        }
+        if let Some(unit) = spec_sink::string_length_unit(&self.state.path_tracker, self.state.string_position) {
+            let length = match unit {
+                LengthUnit::Bytes => range.end - range.start,
+                LengthUnit::Codepoints => self.input.codepoints(range),
+            };
+            self.add_string_length(length);
+        }
         let input = &self.input;
         self.state.current_string.append_range(range, |r, b| input.push_range(r, b));
     }
     fn append_string_single(&mut self, character: u8) {
+        if self.state.string_skipped {
+            return;
+        }
+        if spec_sink::string_length_unit(&self.state.path_tracker, self.state.string_position).is_some() {
+            self.add_string_length(1);
+        }
         let input = &self.input;
         self.state.current_string.append_single(character, |r, b| input.push_range(r, b));
     }
     fn append_string_codepoint(&mut self, codepoint: char) {
+        if self.state.string_skipped {
+            return;
+        }
+        if let Some(unit) = spec_sink::string_length_unit(&self.state.path_tracker, self.state.string_position) {
+            let length = match unit {
+                LengthUnit::Bytes => codepoint.len_utf8(),
+                LengthUnit::Codepoints => 1,
+            };
+            self.add_string_length(length);
+        }
         let input = &self.input;
         self.state.current_string.append_codepoint(codepoint, |r, b| input.push_range(r, b));
     }
@@ -128,31 +320,48 @@ impl<'a, 'b> Sink for StreamingSS<'a, 'b> {
 
         match pos {
             StringPosition::MapKey => {
-                let key = string.owned_to_vec();
-
-                let curr_node_id = self.state.path_tracker.enter_key(key.clone());
-                let key_atom = curr_node_id
-                    .and_then(|node_id| {
-                        let curr_node = self.state.path_tracker.walker.spec.get(node_id);
-                        match curr_node.options.atom_mappings {
-                            Some(ref some) => some.get(&key).cloned(),
-                            None => None,
+                let key = string.into_vec(&self.input);
+                let key_pos = self.state.path_tracker.enter_key(key);
+
+                let key_term = spec_sink::check_key(self.env, &self.state.path_tracker, &key_pos)
+                    .map(|()| {
+                        if self.state.path_tracker.skipping() {
+                            None
+                        } else {
+                            Some(spec_sink::key_term(self.env, &self.state.path_tracker, &key_pos))
                         }
                     });
-
-                if let Some(atom) = key_atom {
-                    self.out_stack.push(atom.encode(self.env));
-                } else {
-                    let mut bin = OwnedNifBinary::new(key.len()).unwrap();
-                    bin.as_mut_slice().write(&key).unwrap();
-                    self.out_stack.push(bin.release(self.env).encode(self.env));
+                match key_term {
+                    Ok(Some(term)) => self.out_stack.push(term),
+                    Ok(None) => (),
+                    Err(failure) => return self.fail(failure),
                 }
             }
             _ => {
-                let string_term = string.to_term(&mut self.input, self.env);
-                self.out_stack.push(string_term);
-
-                let curr_node = self.state.path_tracker.visit_terminal(pos.to_position(), ValueType::String);
+                let curr_node = self.state.string_position.take().unwrap();
+
+                if !self.state.path_tracker.skipping() {
+                    let string_term = {
+                        let tracker = &mut self.state.path_tracker;
+                        let input = &self.input;
+                        let env = self.env;
+                        let length = self.state.string_length;
+                        spec_sink::check_utf8(env, tracker, &string, input)
+                            .and_then(|()| {
+                                if spec_sink::is_array_tag(tracker, curr_node.parent) {
+                                    spec_sink::select_array_tag(env, tracker, &string, input)
+                                } else {
+                                    Ok(())
+                                }
+                            })
+                            .and_then(|()| spec_sink::string_value(env, tracker, curr_node.current,
+                                                                   string, length, input))
+                    };
+                    match string_term {
+                        Ok(term) => self.out_stack.push(term),
+                        Err(failure) => return self.fail(failure),
+                    }
+                }
             }
         }
         self.state.first_needed = self.state.position;
@@ -162,44 +371,74 @@ impl<'a, 'b> Sink for StreamingSS<'a, 'b> {
     fn finalize_map(&mut self, _pos: Position) -> Result<(), Self::Bail> {
         self.state.first_needed = self.state.position;
 
-        let curr_node_id = self.state.path_tracker.exit_map();
+        let missing = self.state.path_tracker.missing_key()
+            .map(|key| bytes_to_term(self.env, key));
+        let defaults = spec_sink::absent_defaults(self.env, &self.state.path_tracker);
 
-        let struct_atom = curr_node_id.current
-            .and_then(|node_id| {
-                let curr_node = self.state.path_tracker.walker.spec.get(node_id);
-                match curr_node.options.struct_atom {
-                    Some(ref atom) => Some(atom.clone()),
-                    None => None,
-                }
-            });
+        let skipping = self.state.path_tracker.in_skipped_subtree();
+        let curr_node = self.state.path_tracker.exit_map();
 
-        if let Some(atom) = struct_atom {
-            let term = self.out_stack.pop().unwrap();
-            self.out_stack.push(term.map_put(
-                ::atoms::__struct__().encode(self.env),
-                atom.encode(self.env)).ok().unwrap());
+        if let Some(key) = missing {
+            let detail = ((::atoms::key(), key), ::atoms::missing()).encode(self.env);
+            let failure = SpecFailure::new(&self.state.path_tracker, ::atoms::spec_mismatch(), detail);
+            return self.fail(failure);
+        }
+
+        if skipping {
+            if let Some(term) = spec_sink::static_value(self.env, &self.state.path_tracker, curr_node.current) {
+                self.out_stack.push(term);
+            }
+            return Ok(());
         }
 
+        let term = self.out_stack.pop().unwrap();
+        let term = spec_sink::map_value(self.env, &self.state.path_tracker, curr_node.current, term, defaults);
+        self.out_stack.push(term);
+
         Ok(())
     }
     fn finalize_array(&mut self, _pos: Position) -> Result<(), Self::Bail> {
-        let term = self.out_stack.pop().unwrap();
-        self.out_stack.push(term.list_reverse().ok().unwrap());
-
         self.state.first_needed = self.state.position;
 
+        let skipping = self.state.path_tracker.in_skipped_subtree();
+        let length = self.state.path_tracker.array_length();
         let curr_node = self.state.path_tracker.exit_array();
 
+        if skipping {
+            if let Some(term) = spec_sink::static_value(self.env, &self.state.path_tracker, curr_node.current) {
+                self.out_stack.push(term);
+            }
+            return Ok(());
+        }
+
+        let term = self.out_stack.pop().unwrap();
+        match spec_sink::array_value(self.env, &self.state.path_tracker, curr_node.current, length, term) {
+            Ok(term) => self.out_stack.push(term),
+            Err(failure) => return self.fail(failure),
+        }
+
         Ok(())
     }
     fn pop_into_map(&mut self) {
+        if self.state.path_tracker.skipping() {
+            return;
+        }
         let value = self.out_stack.pop().unwrap();
         let key = self.out_stack.pop().unwrap();
+        if self.state.path_tracker.discarding() {
+            return;
+        }
         let map = self.out_stack.pop().unwrap();
         self.out_stack.push(map.map_put(key, value).ok().unwrap());
     }
     fn pop_into_array(&mut self) {
+        if self.state.path_tracker.skipping() {
+            return;
+        }
         let value = self.out_stack.pop().unwrap();
+        if self.state.path_tracker.discarding() {
+            return;
+        }
         let array = self.out_stack.pop().unwrap();
         self.out_stack.push(array.list_prepend(value));
     }
@@ -0,0 +1,406 @@
+use rustler::{NifEnv, NifTerm, NifResult, NifEncoder, NifError};
+use rustler::resource::ResourceArc;
+use rustler::types::binary::{NifBinary, OwnedNifBinary};
+use rustler::types::list::NifListIterator;
+use rustler::types::map::NifMapIterator;
+
+use ::num_bigint::BigUint;
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::ops::DerefMut;
+
+mod atoms {
+    rustler_atoms! {
+        atom invalid_value;
+        atom invalid_string;
+
+        atom indent;
+        atom indent_with;
+        atom spaces;
+        atom tabs;
+        atom newline;
+        atom lf;
+        atom crlf;
+        atom colon_space;
+    }
+}
+
+/// Controls the layout of the encoded JSON.
+///
+/// With the default options the output is compact. Setting `indent` to
+/// anything other than 0 enables pretty printing, where every element of a
+/// non-empty array or map is written on its own line.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub indent: usize,
+    pub indent_char: u8,
+    pub newline: &'static [u8],
+    pub colon_space: bool,
+}
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            indent: 0,
+            indent_char: b' ',
+            newline: b"\n",
+            colon_space: false,
+        }
+    }
+}
+
+fn read_opts<'a>(term: NifTerm<'a>) -> NifResult<EncodeOptions> {
+    let iterator: NifListIterator = term.decode()?;
+    let mut opts = EncodeOptions::default();
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::indent() == key {
+            opts.indent = value.decode()?;
+        } else if atoms::indent_with() == key {
+            opts.indent_char = if atoms::spaces() == value {
+                b' '
+            } else if atoms::tabs() == value {
+                b'\t'
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::newline() == key {
+            opts.newline = if atoms::lf() == value {
+                b"\n"
+            } else if atoms::crlf() == value {
+                b"\r\n"
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::colon_space() == key {
+            opts.colon_space = value.decode()?;
+        } else {
+            return Err(NifError::BadArg);
+        }
+    }
+    Ok(opts)
+}
+
+/// A non-empty array or map the encoder is inside of.
+///
+/// The encoder never recurses. What is left of every array and map we are
+/// in is kept as a list term next to its frame, so that the encoder can be
+/// suspended at any point. Only these lists go through the VM when
+/// rescheduling, one per level of nesting, since terms can't be kept in the
+/// resource between calls.
+struct Frame {
+    /// Maps are walked as a list of `{key, value}` pairs.
+    map: bool,
+    /// Whether no element has been written yet.
+    first: bool,
+}
+
+pub struct EncoderIterState {
+    buf: Vec<u8>,
+    frames: Vec<Frame>,
+    opts: EncodeOptions,
+}
+pub struct EncoderIterStateWrapper(Mutex<EncoderIterState>);
+
+enum EncodeError<'a> {
+    InvalidValue(NifTerm<'a>),
+    InvalidString(NifTerm<'a>),
+}
+
+impl<'a> EncodeError<'a> {
+    fn to_term(&self, env: NifEnv<'a>) -> NifTerm<'a> {
+        match *self {
+            EncodeError::InvalidValue(term) =>
+                (::atoms::error(), (atoms::invalid_value(), term)).encode(env),
+            EncodeError::InvalidString(term) =>
+                (::atoms::error(), (atoms::invalid_string(), term)).encode(env),
+        }
+    }
+}
+
+const HEX_DIGITS: &'static [u8; 16] = b"0123456789abcdef";
+
+fn write_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.push(b'"');
+
+    let mut start = 0;
+    for (idx, &byte) in data.iter().enumerate() {
+        let escape: &[u8] = match byte {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x08 => b"\\b",
+            0x0C => b"\\f",
+            _ if byte < 0x20 => b"",
+            _ => continue,
+        };
+
+        buf.extend_from_slice(&data[start..idx]);
+        if escape.len() == 0 {
+            buf.extend_from_slice(b"\\u00");
+            buf.push(HEX_DIGITS[(byte >> 4) as usize]);
+            buf.push(HEX_DIGITS[(byte & 0xF) as usize]);
+        } else {
+            buf.extend_from_slice(escape);
+        }
+        start = idx + 1;
+    }
+    buf.extend_from_slice(&data[start..]);
+
+    buf.push(b'"');
+}
+
+/// Starts a new line at the given depth. Does nothing unless pretty printing
+/// is enabled.
+fn write_newline(buf: &mut Vec<u8>, opts: &EncodeOptions, depth: usize) {
+    if opts.indent == 0 {
+        return;
+    }
+    buf.extend_from_slice(opts.newline);
+    for _ in 0..(depth * opts.indent) {
+        buf.push(opts.indent_char);
+    }
+}
+
+fn write_binary<'a>(buf: &mut Vec<u8>, term: NifTerm<'a>, binary: NifBinary<'a>)
+                    -> Result<(), EncodeError<'a>> {
+    let data = binary.as_slice();
+    if ::std::str::from_utf8(data).is_err() {
+        return Err(EncodeError::InvalidString(term));
+    }
+    write_string(buf, data);
+    Ok(())
+}
+
+/// Writes a bignum by reading it back out of the external term format.
+/// This is the inverse of `numbers::integer_to_bigint_term`.
+fn write_bignum<'a>(buf: &mut Vec<u8>, term: NifTerm<'a>) -> Result<(), EncodeError<'a>> {
+    // http://erlang.org/doc/apps/erts/erl_ext_dist.html#id101259
+    let etf = term.to_binary();
+    let etf = etf.as_slice();
+
+    let (sign, digits) = match etf.get(1) {
+        // Small bignum, 1 byte length
+        Some(&110) if etf.len() >= 4 => (etf[3], &etf[4..]),
+        // Large bignum, 4 byte length
+        Some(&111) if etf.len() >= 7 => (etf[6], &etf[7..]),
+        _ => return Err(EncodeError::InvalidValue(term)),
+    };
+
+    if sign != 0 {
+        buf.push(b'-');
+    }
+    let num = BigUint::from_bytes_le(digits);
+    buf.extend_from_slice(num.to_str_radix(10).as_bytes());
+    Ok(())
+}
+
+fn write_key<'a>(buf: &mut Vec<u8>, key: NifTerm<'a>) -> Result<(), EncodeError<'a>> {
+    if let Ok(binary) = key.decode::<NifBinary>() {
+        write_binary(buf, key, binary)
+    } else if let Ok(atom_str) = key.atom_to_string() {
+        write_string(buf, atom_str.as_bytes());
+        Ok(())
+    } else {
+        Err(EncodeError::InvalidValue(key))
+    }
+}
+
+/// Writes a single value. Terminal values are written directly, non-empty
+/// lists and maps are opened, and a frame is pushed for their contents.
+///
+/// Returns the number of pairs copied out of a map, which counts towards
+/// the work done before rescheduling.
+fn write_value<'a>(env: NifEnv<'a>,
+                   buf: &mut Vec<u8>,
+                   frames: &mut Vec<Frame>,
+                   rests: &mut Vec<NifTerm<'a>>,
+                   term: NifTerm<'a>)
+                   -> Result<usize, EncodeError<'a>> {
+    if let Ok(binary) = term.decode::<NifBinary>() {
+        write_binary(buf, term, binary)?;
+        return Ok(0);
+    }
+
+    if let Ok(boolean) = term.decode::<bool>() {
+        buf.extend_from_slice(if boolean { b"true" } else { b"false" });
+        return Ok(0);
+    }
+    if ::atoms::nil() == term {
+        buf.extend_from_slice(b"null");
+        return Ok(0);
+    }
+    if let Ok(atom_str) = term.atom_to_string() {
+        write_string(buf, atom_str.as_bytes());
+        return Ok(0);
+    }
+
+    if let Ok(num) = term.decode::<i64>() {
+        write!(buf, "{}", num).unwrap();
+        return Ok(0);
+    }
+    if let Ok(num) = term.decode::<u64>() {
+        write!(buf, "{}", num).unwrap();
+        return Ok(0);
+    }
+    if let Ok(num) = term.decode::<f64>() {
+        // The debug representation is the shortest one that roundtrips,
+        // and always includes either a decimal point or an exponent.
+        write!(buf, "{:?}", num).unwrap();
+        return Ok(0);
+    }
+
+    if let Ok(iterator) = term.decode::<NifMapIterator>() {
+        // Map iterators can't be resumed in a later call, so the pairs are
+        // copied into a list.
+        let struct_key = ::atoms::__struct__().encode(env);
+        let pairs: Vec<(NifTerm, NifTerm)> = iterator
+            .filter(|&(key, _)| key != struct_key)
+            .collect();
+
+        buf.push(b'{');
+        if pairs.len() == 0 {
+            buf.push(b'}');
+        } else {
+            frames.push(Frame { map: true, first: true });
+            rests.push(pairs.encode(env));
+        }
+        return Ok(pairs.len());
+    }
+
+    if term.decode::<NifListIterator>().is_ok() {
+        buf.push(b'[');
+        if term.list_get_cell().is_err() {
+            buf.push(b']');
+        } else {
+            frames.push(Frame { map: false, first: true });
+            rests.push(term);
+        }
+        return Ok(0);
+    }
+
+    write_bignum(buf, term)?;
+    Ok(0)
+}
+
+/// Runs the encoder until every array and map is closed, or until about
+/// 40_000 bytes have been written, whichever comes first.
+///
+/// Returns `Ok(true)` when encoding is finished.
+fn encode_inner<'a>(env: NifEnv<'a>,
+                    rests: &mut Vec<NifTerm<'a>>,
+                    iter_state: &mut EncoderIterState)
+                    -> Result<bool, EncodeError<'a>> {
+    let opts = &iter_state.opts;
+    let frames = &mut iter_state.frames;
+    let buf = &mut iter_state.buf;
+    let next_reschedule = buf.len() + 40_000;
+    let mut copied = 0;
+
+    while buf.len() + copied < next_reschedule {
+        let (first, map) = match frames.last() {
+            None => return Ok(true),
+            Some(frame) => (frame.first, frame.map),
+        };
+        let rest = rests.pop().unwrap();
+
+        let (element, rest) = match rest.list_get_cell() {
+            Ok(cell) => cell,
+            Err(_) => {
+                // Lists are only ever walked to the end, anything else is
+                // the tail of an improper list.
+                if rest.decode::<NifListIterator>().is_err() {
+                    return Err(EncodeError::InvalidValue(rest));
+                }
+                frames.pop();
+                write_newline(buf, opts, frames.len());
+                buf.push(if map { b'}' } else { b']' });
+                continue;
+            }
+        };
+        rests.push(rest);
+        frames.last_mut().unwrap().first = false;
+
+        if !first {
+            buf.push(b',');
+        }
+        write_newline(buf, opts, frames.len());
+        let value = if map {
+            let (key, value): (NifTerm, NifTerm) = match element.decode() {
+                Ok(pair) => pair,
+                Err(_) => return Err(EncodeError::InvalidValue(element)),
+            };
+            write_key(buf, key)?;
+            buf.push(b':');
+            if opts.colon_space {
+                buf.push(b' ');
+            }
+            value
+        } else {
+            element
+        };
+        copied += write_value(env, buf, frames, rests, value)?;
+    }
+
+    Ok(frames.len() == 0)
+}
+
+fn encode_step<'a>(env: NifEnv<'a>,
+                   mut rests: Vec<NifTerm<'a>>,
+                   resource: ResourceArc<EncoderIterStateWrapper>)
+                   -> NifTerm<'a> {
+    let result = {
+        let mut resource_inner_guard = resource.0.lock().unwrap();
+        let iter_state = resource_inner_guard.deref_mut();
+        encode_inner(env, &mut rests, iter_state)
+            .map(|finished| {
+                if finished {
+                    let buf = &iter_state.buf;
+                    let mut bin = OwnedNifBinary::new(buf.len()).unwrap();
+                    bin.as_mut_slice().write(buf).unwrap();
+                    Some(bin.release(env).encode(env))
+                } else {
+                    None
+                }
+            })
+    };
+
+    match result {
+        Ok(Some(binary)) => (::atoms::ok(), binary).encode(env),
+        Ok(None) => (::atoms::iter(), rests, resource).encode(env),
+        Err(error) => error.to_term(env),
+    }
+}
+
+pub fn encode<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
+    let opts = read_opts(args[1])?;
+
+    let mut iter_state = EncoderIterState {
+        buf: Vec::new(),
+        frames: Vec::new(),
+        opts: opts,
+    };
+    let mut rests: Vec<NifTerm<'a>> = Vec::new();
+    if let Err(error) = write_value(env, &mut iter_state.buf, &mut iter_state.frames,
+                                    &mut rests, args[0]) {
+        return Ok(error.to_term(env));
+    }
+    let resource = ResourceArc::new(EncoderIterStateWrapper(Mutex::new(iter_state)));
+
+    Ok(encode_step(env, rests, resource))
+}
+
+pub fn encode_iter<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
+    let rests: Vec<NifTerm<'a>> = args[0].decode()?;
+    let resource: ResourceArc<EncoderIterStateWrapper> = args[1].decode()?;
+
+    // The lists have to line up with the frames in the resource.
+    if rests.len() != resource.0.lock().unwrap().frames.len() {
+        return Err(NifError::BadArg);
+    }
+
+    Ok(encode_step(env, rests, resource))
+}
@@ -0,0 +1,127 @@
+//! Errors returned from parsing.
+//!
+//! All parse functions report errors as `{:error, %Juicy.Error{}}`, with
+//! `reason` telling what kind of failure it was and `detail` holding data
+//! specific to that reason.
+
+use ::rustler::{NifEnv, NifTerm, NifEncoder, NifResult, NifError};
+use ::rustler::types::atom::NifAtom;
+use ::rustler::types::map::map_new;
+
+use ::iterative_json_parser::Unexpected;
+
+use ::location::{locate, LineCounter};
+use ::strings::bytes_to_term;
+
+mod atoms {
+    rustler_atoms! {
+        atom juicy_error = "Elixir.Juicy.Error";
+        atom __exception__;
+        atom reason;
+        atom detail;
+        atom position;
+        atom path;
+        atom line;
+        atom column;
+        atom excerpt;
+        atom infinity;
+    }
+}
+
+/// Maximum nesting of maps and arrays, unless the `max_depth` option says
+/// otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+/// Reads the `max_depth` option, a positive integer or `:infinity`.
+pub fn read_max_depth<'a>(term: NifTerm<'a>) -> NifResult<usize> {
+    if atoms::infinity() == term {
+        return Ok(::std::usize::MAX);
+    }
+    match term.decode::<usize>() {
+        Ok(max_depth) if max_depth > 0 => Ok(max_depth),
+        _ => Err(NifError::BadArg),
+    }
+}
+
+pub struct ParseFailure<'a> {
+    pub reason: NifAtom,
+    /// Byte offset in the input.
+    pub position: usize,
+    /// Path to the value that failed, `nil` when it is not known.
+    pub path: NifTerm<'a>,
+    pub detail: NifTerm<'a>,
+}
+
+impl<'a> ParseFailure<'a> {
+
+    /// Makes the `{:error, %Juicy.Error{}}` term. The line, column and
+    /// excerpt are worked out from the input here.
+    ///
+    /// `byte` returns the byte at the given position, or `None` if it is not
+    /// available. `lines` should have counted the input before the first
+    /// available byte.
+    pub fn to_term<F>(&self, env: NifEnv<'a>, byte: F, lines: &LineCounter) -> NifTerm<'a>
+        where F: Fn(usize) -> Option<u8> {
+
+        let location = locate(byte, lines, self.position);
+        let fields: [(NifAtom, NifTerm<'a>); 9] = [
+            (::atoms::__struct__(), atoms::juicy_error().encode(env)),
+            (atoms::__exception__(), true.encode(env)),
+            (atoms::reason(), self.reason.encode(env)),
+            (atoms::detail(), self.detail),
+            (atoms::position(), (self.position as u64).encode(env)),
+            (atoms::path(), self.path),
+            (atoms::line(), (location.line as u64).encode(env)),
+            (atoms::column(), (location.column as u64).encode(env)),
+            (atoms::excerpt(), bytes_to_term(env, &location.excerpt)),
+        ];
+
+        let mut map = map_new(env);
+        for &(key, value) in fields.iter() {
+            map = map.map_put(key.encode(env), value).ok().unwrap();
+        }
+        (::atoms::error(), map).encode(env)
+    }
+
+}
+
+/// Failure for parser results that should not be possible. These are
+/// reported like any other error rather than panicking, since the input is
+/// untrusted.
+pub fn internal_error<'a>(env: NifEnv<'a>, position: usize) -> ParseFailure<'a> {
+    ParseFailure {
+        reason: ::atoms::internal_error(),
+        position: position,
+        path: ::atoms::nil().encode(env),
+        detail: ::atoms::nil().encode(env),
+    }
+}
+
+/// The reason for a syntax error the parser ran into at `position`.
+///
+/// The parser reports running out of input as an unexpected character in
+/// some states, so that is told apart by there being no byte at the position.
+pub fn classify_unexpected<F>(byte: F, position: usize, reason: &Unexpected) -> NifAtom
+    where F: Fn(usize) -> Option<u8> {
+
+    match *reason {
+        Unexpected::EndOfInput => ::atoms::unexpected_eof(),
+        Unexpected::InvalidEscape => ::atoms::invalid_escape(),
+        _ if byte(position).is_none() => ::atoms::unexpected_eof(),
+        _ => ::atoms::unexpected_character(),
+    }
+}
+
+/// Position of the first byte after `position` that is not whitespace, if
+/// there is one.
+pub fn find_trailing<F>(byte: F, mut position: usize) -> Option<usize>
+    where F: Fn(usize) -> Option<u8> {
+
+    loop {
+        match byte(position) {
+            Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => position += 1,
+            Some(_) => return Some(position),
+            None => return None,
+        }
+    }
+}
@@ -4,6 +4,11 @@ use ::rustler::{NifTerm, NifEnv};
 pub mod single;
 pub mod streaming;
 
+/// Whether the byte starts a UTF-8 codepoint, as opposed to continuing one.
+pub fn starts_codepoint(byte: u8) -> bool {
+    byte & 0xC0 != 0x80
+}
+
 /// Things implementing this trait are responsible for providing
 /// input data to both the parser and to the code constructing terms
 /// from the parser output.
@@ -15,5 +20,9 @@ pub mod streaming;
 pub trait InputProvider<DataResponse> {
     fn byte(&self, pos: usize) -> DataResponse;
     fn push_range(&self, range: PRange, buf: &mut Vec<u8>);
+    /// Number of UTF-8 codepoints starting in the range.
+    fn codepoints(&self, range: PRange) -> usize;
+    /// Position of the first byte in the range that is not valid UTF-8.
+    fn invalid_utf8(&self, range: PRange) -> Option<usize>;
     fn range_to_term<'a>(&self, env: NifEnv<'a>, range: PRange) -> NifTerm<'a>;
 }
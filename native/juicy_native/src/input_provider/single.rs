@@ -1,7 +1,7 @@
 use ::rustler::{NifEnv, NifTerm, NifEncoder};
 use ::rustler::types::binary::NifBinary;
 
-use super::InputProvider;
+use super::{InputProvider, starts_codepoint};
 
 use ::iterative_json_parser::Range as PRange;
 
@@ -29,6 +29,17 @@ impl<'a> InputProvider<Option<u8>> for SingleBinaryProvider<'a> {
         buf.extend_from_slice(&bin[range.start..range.end]);
     }
 
+    fn codepoints(&self, range: PRange) -> usize {
+        let bin = self.binary.as_slice();
+        bin[range.start..range.end].iter().filter(|&&byte| starts_codepoint(byte)).count()
+    }
+
+    fn invalid_utf8(&self, range: PRange) -> Option<usize> {
+        let bin = self.binary.as_slice();
+        ::std::str::from_utf8(&bin[range.start..range.end]).err()
+            .map(|error| range.start + error.valid_up_to())
+    }
+
     fn range_to_term<'b>(&self, env: NifEnv<'b>, range: PRange) -> NifTerm<'b> {
         self.binary
             .make_subbinary(range.start, range.end-range.start)
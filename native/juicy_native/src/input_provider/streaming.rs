@@ -1,10 +1,12 @@
 use std::ops::Range;
 use std::io::Write;
+use std::cmp::{min, max};
 
 use ::rustler::{NifEnv, NifTerm, NifEncoder};
 use ::rustler::types::binary::{NifBinary, OwnedNifBinary};
 
-use super::InputProvider;
+use super::{InputProvider, starts_codepoint};
+use ::location::LineCounter;
 
 use ::iterative_json_parser::Range as PRange;
 
@@ -14,38 +16,213 @@ pub enum StreamingInputResult {
     Eof,
 }
 
+/// Input copied out of binaries that have been released while it was still
+/// needed, so that holding on to it doesn't keep every binary around.
+pub struct HeldInput {
+    start: usize,
+    bytes: Vec<u8>,
+}
+
+impl HeldInput {
+
+    pub fn new() -> HeldInput {
+        HeldInput {
+            start: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.start..(self.start + self.bytes.len())
+    }
+
+    /// Drops the held input once nothing in it is needed anymore, counting
+    /// its lines.
+    pub fn release(&mut self, first_needed: usize, lines: &mut LineCounter) {
+        if !self.bytes.is_empty() && first_needed >= self.range().end {
+            lines.count(self.start, &self.bytes);
+            self.bytes.clear();
+        }
+    }
+
+    /// Copies the input from `first_needed` on out of the binaries that
+    /// are about to be released, the ones ending before `keep_from`. The
+    /// binaries are ordered newest first.
+    pub fn hold(&mut self, binaries: &[(Range<usize>, NifBinary)], first_needed: usize,
+                keep_from: usize) {
+        if self.bytes.is_empty() {
+            self.start = first_needed;
+        }
+        for &(ref range, bin) in binaries.iter().rev() {
+            if range.end >= keep_from {
+                break;
+            }
+            let start = max(self.range().end, range.start);
+            if start < range.end {
+                self.bytes.extend_from_slice(&bin.as_slice()[(start - range.start)..]);
+            }
+        }
+    }
+
+    /// Where the held input starts, if there is any.
+    pub fn start(&self) -> Option<usize> {
+        if self.bytes.is_empty() {
+            None
+        } else {
+            Some(self.start)
+        }
+    }
+
+}
+
+/// Validates UTF-8 split over several slices, without copying more than
+/// a codepoint that crosses from one slice to the next.
+struct Utf8Validator {
+    /// The start of a codepoint that the last slice ended in the middle of.
+    partial: [u8; 4],
+    partial_len: usize,
+    /// Where the partial codepoint starts in the input.
+    partial_position: usize,
+    invalid: Option<usize>,
+}
+
+impl Utf8Validator {
+
+    fn new() -> Utf8Validator {
+        Utf8Validator {
+            partial: [0; 4],
+            partial_len: 0,
+            partial_position: 0,
+            invalid: None,
+        }
+    }
+
+    /// Takes the next slice, which starts at `position` in the input.
+    fn feed(&mut self, position: usize, slice: &[u8]) {
+        if self.invalid.is_some() {
+            return;
+        }
+
+        let mut skip = 0;
+        if self.partial_len > 0 {
+            // Finish the partial codepoint first. It is at most 4 bytes, so
+            // a valid one is complete once 4 bytes are there.
+            let held = self.partial_len;
+            let taken = min(4 - held, slice.len());
+            self.partial[held..(held + taken)].copy_from_slice(&slice[..taken]);
+            self.partial_len += taken;
+            match ::std::str::from_utf8(&self.partial[..self.partial_len]) {
+                Ok(_) => skip = taken,
+                Err(ref error) if error.valid_up_to() > held => skip = error.valid_up_to() - held,
+                Err(ref error) if error.error_len().is_none() => return,
+                Err(_) => {
+                    self.invalid = Some(self.partial_position);
+                    return;
+                }
+            }
+            self.partial_len = 0;
+        }
+
+        let rest = &slice[skip..];
+        if let Err(error) = ::std::str::from_utf8(rest) {
+            let offset = error.valid_up_to();
+            if error.error_len().is_some() {
+                self.invalid = Some(position + skip + offset);
+            } else {
+                self.partial_len = rest.len() - offset;
+                self.partial[..self.partial_len].copy_from_slice(&rest[offset..]);
+                self.partial_position = position + skip + offset;
+            }
+        }
+    }
+
+    /// The position of the first invalid byte, if there is one.
+    fn finish(self) -> Option<usize> {
+        if self.invalid.is_none() && self.partial_len > 0 {
+            Some(self.partial_position)
+        } else {
+            self.invalid
+        }
+    }
+
+}
+
 /// Provides input from a set of binaries.
 pub struct StreamingInputProvider<'a, 'b> where 'a: 'b {
-    pub binaries: &'b [(Range<usize>, NifBinary<'a>)]
+    pub binaries: &'b [(Range<usize>, NifBinary<'a>)],
+    /// Comes before the input in the binaries.
+    pub held: HeldInput,
+    /// Set when there is no more input coming after these binaries.
+    pub finished: bool,
+}
+
+impl<'a, 'b> StreamingInputProvider<'a, 'b> {
+    /// The byte at the given position, if it is available.
+    pub fn available_byte(&self, pos: usize) -> Option<u8> {
+        match self.byte(pos) {
+            StreamingInputResult::Ok(byte) => Some(byte),
+            _ => None,
+        }
+    }
+
+    /// Calls `f` with the parts of the input in `range` in order, along with
+    /// the position each of them starts at.
+    fn each_slice<F>(&self, range: PRange, mut f: F) where F: FnMut(usize, &[u8]) {
+        let mut start = range.start;
+        let held = self.held.range();
+        if start >= held.start && start < held.end {
+            let end = min(range.end, held.end);
+            f(start, &self.held.bytes[(start - held.start)..(end - held.start)]);
+            start = end;
+        }
+        for &(ref b_range, bin) in self.binaries.iter().rev() {
+            let s = max(start, b_range.start);
+            let e = min(range.end, b_range.end);
+            if s < e {
+                f(s, &bin.as_slice()[(s - b_range.start)..(e - b_range.start)]);
+            }
+        }
+    }
 }
 
 impl<'a, 'b> InputProvider<StreamingInputResult> for StreamingInputProvider<'a, 'b> {
 
     fn byte(&self, pos: usize) -> StreamingInputResult {
+        let held = self.held.range();
+        if held.start <= pos && held.end > pos {
+            return StreamingInputResult::Ok(self.held.bytes[pos - held.start]);
+        }
         for &(ref range, bin) in self.binaries {
             if range.start <= pos && range.end > pos {
                 return StreamingInputResult::Ok(bin.as_slice()[pos - range.start]);
             }
         }
-        StreamingInputResult::AwaitInput
+        // The binaries are ordered newest first, so the first one ends the
+        // input.
+        let end = self.binaries.first().map(|&(ref range, _)| range.end).unwrap_or(0);
+        if self.finished && pos >= end {
+            StreamingInputResult::Eof
+        } else {
+            StreamingInputResult::AwaitInput
+        }
     }
 
     fn push_range(&self, range: PRange, buf: &mut Vec<u8>) {
-        for &(ref b_range, bin) in self.binaries.iter().rev() {
-
-            let s = if range.start < b_range.start {
-                0
-            } else {
-                range.start - b_range.start
-            };
-            let e = range.end.wrapping_sub(b_range.start);
+        self.each_slice(range, |_, slice| buf.extend_from_slice(slice));
+    }
 
-            let ss = if s < bin.len() { s } else { bin.len() };
-            let ee = if e < bin.len() { e } else { bin.len() };
+    fn codepoints(&self, range: PRange) -> usize {
+        let mut count = 0;
+        self.each_slice(range, |_, slice| {
+            count += slice.iter().filter(|&&byte| starts_codepoint(byte)).count();
+        });
+        count
+    }
 
-            let slice = &bin.as_slice()[ss..ee];
-            buf.extend_from_slice(&slice);
-        }
+    fn invalid_utf8(&self, range: PRange) -> Option<usize> {
+        let mut validator = Utf8Validator::new();
+        self.each_slice(range, |position, slice| validator.feed(position, slice));
+        validator.finish()
     }
 
     fn range_to_term<'c>(&self, env: NifEnv<'c>, range: PRange) -> NifTerm<'c> {
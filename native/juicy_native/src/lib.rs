@@ -9,6 +9,10 @@ extern crate num_traits;
 extern crate num_bigint;
 
 use rustler::{NifEnv, NifTerm, NifResult, NifEncoder};
+use rustler::resource::ResourceArc;
+use rustler::types::binary::NifBinary;
+
+use std::sync::Arc;
 
 extern crate iterative_json_parser;
 
@@ -17,17 +21,46 @@ mod strings;
 mod tree_spec;
 mod input_provider;
 mod path_tracker;
+mod lookahead;
+mod location;
+mod errors;
+mod spec_sink;
 
 mod basic;
 mod basic_spec;
 mod streaming;
+mod encoder;
 
 mod atoms {
     rustler_atoms! {
         atom ok;
         atom nil;
         atom error;
-        atom unexpected;
+        atom unexpected_eof;
+        atom unexpected_character;
+        atom invalid_escape;
+        atom invalid_utf8;
+        atom trailing_data;
+        atom depth_limit;
+        atom invalid_key;
+        atom internal_error;
+        atom unknown_key;
+        atom tuple_length;
+        atom invalid_enum;
+        atom unknown_tag;
+        atom missing_tag;
+        atom spec_mismatch;
+        atom keys;
+        atom key;
+        atom missing;
+        atom string_too_long;
+        atom string_too_short;
+        atom invalid_number;
+        atom not_integer;
+        atom negative;
+        atom out_of_range;
+        atom min;
+        atom max;
         atom iter;
         atom streamed;
         atom yield_ = "yield";
@@ -40,16 +73,21 @@ mod atoms {
 rustler_export_nifs! {
     "Elixir.Juicy.Native",
     [
-        ("parse_init", 1, basic::parse),
+        ("parse_init", 2, basic::parse),
         ("parse_iter", 3, basic::parse_iter),
 
-        ("spec_parse_init", 2, basic_spec::parse_init),
+        ("spec_parse_init", 3, basic_spec::parse_init),
         ("spec_parse_iter", 1, basic_spec::parse_iter),
 
-        ("stream_parse_init", 1, streaming::parse_init),
-        ("stream_parse_iter", 2, streaming::parse_iter),
+        ("stream_parse_init", 2, streaming::parse_init),
+        ("stream_parse_iter", 3, streaming::parse_iter),
+
+        ("encode_init", 2, encoder::encode),
+        ("encode_iter", 2, encoder::encode_iter),
 
         ("validate_spec", 1, validate_spec),
+        ("compile_spec", 1, compile_spec),
+        ("compile_selector", 1, compile_selector),
     ],
     Some(on_init)
 }
@@ -61,9 +99,32 @@ fn validate_spec<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm
     }
 }
 
+fn compile_spec<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
+    match tree_spec::spec_from_term(args[0]) {
+        Ok(spec) => {
+            let compiled = tree_spec::CompiledSpec(Arc::new(spec));
+            Ok((atoms::ok(), ResourceArc::new(compiled)).encode(env))
+        }
+        Err(_) => Ok(atoms::error().encode(env)),
+    }
+}
+
+fn compile_selector<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
+    let selector: NifBinary = args[0].decode()?;
+    match tree_spec::spec_from_selector(selector.as_slice()) {
+        Some(spec) => {
+            let compiled = tree_spec::CompiledSpec(Arc::new(spec));
+            Ok((atoms::ok(), ResourceArc::new(compiled)).encode(env))
+        }
+        None => Ok(atoms::error().encode(env)),
+    }
+}
+
 fn on_init<'a>(env: NifEnv<'a>, _load_info: NifTerm<'a>) -> bool {
     resource_struct_init!(basic::IterStateWrapper, env);
     resource_struct_init!(basic_spec::BasicSpecIterStateWrapper, env);
     resource_struct_init!(streaming::StreamingIterStateWrapper, env);
+    resource_struct_init!(encoder::EncoderIterStateWrapper, env);
+    resource_struct_init!(tree_spec::CompiledSpec, env);
     true
 }
@@ -0,0 +1,169 @@
+//! Describes where in the input an error happened.
+//!
+//! Nothing is tracked while parsing, the location is worked out from the
+//! input once an error is reported. Input that is no longer available at
+//! that point is summarized by a `LineCounter`.
+
+use std::cmp::{max, min};
+
+use ::input_provider::starts_codepoint;
+
+/// Maximum number of bytes included on each side of the error position.
+const EXCERPT_CONTEXT: usize = 20;
+
+/// Summarizes input that has been released, for locating errors past it.
+///
+/// Only newlines are counted as the input goes by. Columns are worked out
+/// when an error is reported, from the end of the current line that is
+/// kept here and the input that is still available.
+#[derive(Debug, Clone)]
+pub struct LineCounter {
+    /// Number of newlines counted.
+    newlines: usize,
+    /// Position the current line starts at.
+    line_start: usize,
+    /// Codepoints on the current line before `tail`.
+    dropped_codepoints: usize,
+    /// The last bytes counted on the current line, for the excerpt and
+    /// column.
+    tail: Vec<u8>,
+    /// Position up to which the input has been counted.
+    counted_to: usize,
+}
+
+impl LineCounter {
+
+    pub fn new() -> LineCounter {
+        LineCounter {
+            newlines: 0,
+            line_start: 0,
+            dropped_codepoints: 0,
+            tail: Vec::new(),
+            counted_to: 0,
+        }
+    }
+
+    /// Counts the bytes starting at position `start`. Bytes that have
+    /// already been counted are skipped.
+    pub fn count(&mut self, start: usize, bytes: &[u8]) {
+        let skip = self.counted_to.saturating_sub(start);
+        if skip >= bytes.len() {
+            return;
+        }
+        let bytes = &bytes[skip..];
+        let start = start + skip;
+
+        let rest = match bytes.iter().rposition(|&byte| byte == b'\n') {
+            Some(last) => {
+                self.newlines += bytes[..last].iter().filter(|&&byte| byte == b'\n').count() + 1;
+                self.line_start = start + last + 1;
+                self.dropped_codepoints = 0;
+                self.tail.clear();
+                &bytes[(last + 1)..]
+            }
+            None => bytes,
+        };
+
+        // Only the end of the line is kept, what comes before it is
+        // summarized by its number of codepoints.
+        let excess = (self.tail.len() + rest.len()).saturating_sub(EXCERPT_CONTEXT);
+        let from_tail = min(excess, self.tail.len());
+        self.dropped_codepoints += count_codepoints(&self.tail[..from_tail]);
+        self.dropped_codepoints += count_codepoints(&rest[..(excess - from_tail)]);
+        self.tail.drain(..from_tail);
+        self.tail.extend_from_slice(&rest[(excess - from_tail)..]);
+        self.counted_to = start + bytes.len();
+    }
+
+    /// Position the kept bytes of the current line start at.
+    fn tail_start(&self) -> usize {
+        self.counted_to - self.tail.len()
+    }
+
+}
+
+fn count_codepoints(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&byte| starts_codepoint(byte)).count()
+}
+
+pub struct Location {
+    /// 1-based line.
+    pub line: usize,
+    /// 1-based column, in codepoints.
+    pub column: usize,
+    /// The input around the error, limited to the line it is on.
+    pub excerpt: Vec<u8>,
+}
+
+/// Finds the location of `position`.
+///
+/// `byte` returns the byte at the given position, or `None` if it is not
+/// available. `counter` should have counted the input before the first
+/// available byte.
+pub fn locate<F>(byte: F, counter: &LineCounter, position: usize) -> Location
+    where F: Fn(usize) -> Option<u8> {
+
+    let tail_start = counter.tail_start();
+    let byte = |pos: usize| {
+        if pos >= counter.counted_to {
+            byte(pos)
+        } else if pos >= tail_start {
+            Some(counter.tail[pos - tail_start])
+        } else {
+            None
+        }
+    };
+
+    let mut newlines = counter.newlines;
+    let mut line_start = counter.line_start;
+    let mut current = counter.counted_to;
+    while current < position {
+        match byte(current) {
+            Some(b'\n') => {
+                newlines += 1;
+                line_start = current + 1;
+            }
+            Some(_) => (),
+            None => break,
+        }
+        current += 1;
+    }
+
+    let column_from = max(line_start, tail_start);
+    let mut column = (column_from..current)
+        .filter(|&pos| byte(pos).map(starts_codepoint).unwrap_or(false))
+        .count();
+    if line_start == counter.line_start {
+        column += counter.dropped_codepoints;
+    }
+
+    let is_excerpt_byte = |pos: usize| {
+        match byte(pos) {
+            Some(b'\n') | Some(b'\r') | None => false,
+            Some(_) => true,
+        }
+    };
+
+    let mut start = position;
+    while start > 0 && position - start < EXCERPT_CONTEXT && is_excerpt_byte(start - 1) {
+        start -= 1;
+    }
+    let mut end = position;
+    while end - position < EXCERPT_CONTEXT && is_excerpt_byte(end) {
+        end += 1;
+    }
+
+    // Don't cut codepoints in half at the edges.
+    while start < end && !byte(start).map(starts_codepoint).unwrap_or(true) {
+        start += 1;
+    }
+    while end > start && end > position && byte(end).map(|b| !starts_codepoint(b)).unwrap_or(false) {
+        end -= 1;
+    }
+
+    Location {
+        line: newlines + 1,
+        column: column + 1,
+        excerpt: (start..end).filter_map(|pos| byte(pos)).collect(),
+    }
+}
@@ -0,0 +1,269 @@
+//! Scans ahead in the input without involving the parser.
+//!
+//! Used by matchers that need to know about a value later in the input
+//! before they can decide how to parse what comes before it. The scanner is
+//! only concerned with finding its way through valid JSON, any errors are
+//! left for the parser to report when it gets there.
+
+pub enum LookaheadResult {
+    /// The key was found. Contains the value if it was a string.
+    Found(Option<Vec<u8>>),
+    /// The object ended without containing the key.
+    Missing,
+    /// The input ended before the scan could complete.
+    Incomplete,
+    /// The input is not valid JSON.
+    Invalid,
+    /// The scan went through as much input as it was allowed to, and should
+    /// be continued later.
+    Suspended,
+}
+
+struct Scanner<F> where F: Fn(usize) -> Option<u8> {
+    byte: F,
+    position: usize,
+}
+
+// Errors in the scanner are represented as a `LookaheadResult` so that they
+// can be propagated with `?`.
+type ScanResult<T> = Result<T, LookaheadResult>;
+
+impl<F> Scanner<F> where F: Fn(usize) -> Option<u8> {
+
+    fn peek(&self) -> ScanResult<u8> {
+        (self.byte)(self.position).ok_or(LookaheadResult::Incomplete)
+    }
+
+    fn next(&mut self) -> ScanResult<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, expected: u8) -> ScanResult<()> {
+        if self.next()? == expected {
+            Ok(())
+        } else {
+            Err(LookaheadResult::Invalid)
+        }
+    }
+
+    fn read_hex(&mut self) -> ScanResult<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = (self.next()? as char).to_digit(16).ok_or(LookaheadResult::Invalid)?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    /// Reads a string, positioned at the opening quote. The string is only
+    /// unescaped when `buf` is given.
+    fn read_string(&mut self, mut buf: Option<&mut Vec<u8>>) -> ScanResult<()> {
+        self.expect(b'"')?;
+        loop {
+            let byte = match self.next()? {
+                b'"' => return Ok(()),
+                b'\\' => {
+                    match self.next()? {
+                        b'"' => b'"',
+                        b'\\' => b'\\',
+                        b'/' => b'/',
+                        b'b' => 0x08,
+                        b'f' => 0x0c,
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'u' => {
+                            let mut codepoint = self.read_hex()?;
+                            if codepoint >= 0xD800 && codepoint < 0xDC00 {
+                                self.expect(b'\\')?;
+                                self.expect(b'u')?;
+                                let low = self.read_hex()?;
+                                if low < 0xDC00 || low > 0xDFFF {
+                                    return Err(LookaheadResult::Invalid);
+                                }
+                                codepoint = 0x10000 + ((codepoint - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            let character = ::std::char::from_u32(codepoint)
+                                .ok_or(LookaheadResult::Invalid)?;
+                            if let Some(ref mut buf) = buf {
+                                let mut utf8 = [0; 4];
+                                buf.extend_from_slice(character.encode_utf8(&mut utf8).as_bytes());
+                            }
+                            continue;
+                        }
+                        _ => return Err(LookaheadResult::Invalid),
+                    }
+                }
+                byte => byte,
+            };
+            if let Some(ref mut buf) = buf {
+                buf.push(byte);
+            }
+        }
+    }
+
+}
+
+/// Reads the string starting at `start`, which is known to be complete.
+fn decode_string<F>(byte: F, start: usize) -> ScanResult<Vec<u8>>
+    where F: Fn(usize) -> Option<u8> {
+
+    let mut scanner = Scanner {
+        byte: byte,
+        position: start,
+    };
+    let mut value = Vec::new();
+    scanner.read_string(Some(&mut value))?;
+    Ok(value)
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    match byte {
+        b' ' | b'\t' | b'\n' | b'\r' => true,
+        _ => false,
+    }
+}
+
+/// Where in the object a `KeyScan` is.
+#[derive(Debug, Copy, Clone)]
+enum Phase {
+    /// Right after the opening brace, where the object may also end.
+    Start,
+    /// Before a key, after a comma.
+    Key,
+    /// In a string starting at `start`. It is decoded in one go once its
+    /// end is found. `tag` is set for the value of the key looked for.
+    String { start: usize, escaped: bool, tag: bool },
+    /// Before the colon following a key.
+    Colon { matched: bool },
+    /// Before the value of a key.
+    BeforeValue { matched: bool },
+    /// Skipping over the value of a key that is not the one looked for.
+    Value { depth: usize, string: bool, escaped: bool },
+    /// After a value, where a comma or the end of the object follows.
+    AfterValue,
+}
+
+/// Looks for a key in an object, one byte at a time.
+///
+/// The scan can be suspended when the input runs out or the budget for it
+/// is used up, and picks up where it left off when run again. Nothing
+/// before `first_needed` is read again.
+#[derive(Debug, Clone)]
+pub struct KeyScan {
+    position: usize,
+    phase: Phase,
+}
+
+impl KeyScan {
+
+    /// Starts a scan of the object at `start`, which should be right after
+    /// the opening brace.
+    pub fn new(start: usize) -> KeyScan {
+        KeyScan {
+            position: start,
+            phase: Phase::Start,
+        }
+    }
+
+    /// The first position the scan still needs to read.
+    pub fn first_needed(&self) -> usize {
+        match self.phase {
+            Phase::String { start, .. } => start,
+            _ => self.position,
+        }
+    }
+
+    /// Goes on with the scan for `key`, through at most `budget` bytes.
+    ///
+    /// `byte` returns the byte at the given position, or `None` if it is
+    /// not available.
+    pub fn run<F>(&mut self, byte: F, key: &[u8], budget: usize) -> LookaheadResult
+        where F: Fn(usize) -> Option<u8> {
+
+        let end = self.position + budget;
+        loop {
+            if self.position >= end {
+                return LookaheadResult::Suspended;
+            }
+            let current = match byte(self.position) {
+                Some(current) => current,
+                None => return LookaheadResult::Incomplete,
+            };
+            let whitespace = is_whitespace(current);
+
+            let phase = self.phase;
+            self.phase = match phase {
+                Phase::Start | Phase::Key if whitespace => phase,
+                Phase::Start if current == b'}' => return LookaheadResult::Missing,
+                Phase::Start | Phase::Key if current == b'"' =>
+                    Phase::String { start: self.position, escaped: false, tag: false },
+                Phase::Start | Phase::Key => return LookaheadResult::Invalid,
+
+                Phase::String { start, escaped: true, tag } =>
+                    Phase::String { start: start, escaped: false, tag: tag },
+                Phase::String { start, tag, .. } if current == b'\\' =>
+                    Phase::String { start: start, escaped: true, tag: tag },
+                Phase::String { start, tag, .. } if current == b'"' => {
+                    let value = match decode_string(&byte, start) {
+                        Ok(value) => value,
+                        Err(result) => return result,
+                    };
+                    if tag {
+                        return LookaheadResult::Found(Some(value));
+                    }
+                    Phase::Colon { matched: &value[..] == key }
+                }
+                Phase::String { .. } => phase,
+
+                Phase::Colon { .. } if whitespace => phase,
+                Phase::Colon { matched } if current == b':' => Phase::BeforeValue { matched: matched },
+                Phase::Colon { .. } => return LookaheadResult::Invalid,
+
+                Phase::BeforeValue { .. } if whitespace => phase,
+                Phase::BeforeValue { matched: true } if current == b'"' =>
+                    Phase::String { start: self.position, escaped: false, tag: true },
+                Phase::BeforeValue { matched: true } => return LookaheadResult::Found(None),
+                Phase::BeforeValue { matched: false } => {
+                    // The first byte is part of the value.
+                    self.phase = Phase::Value { depth: 0, string: false, escaped: false };
+                    continue;
+                }
+
+                Phase::Value { depth, string: true, escaped } => {
+                    let (string, escaped) = match current {
+                        _ if escaped => (true, false),
+                        b'\\' => (true, true),
+                        b'"' => (false, false),
+                        _ => (true, false),
+                    };
+                    Phase::Value { depth: depth, string: string, escaped: escaped }
+                }
+                Phase::Value { depth, .. } => {
+                    match current {
+                        b'"' => Phase::Value { depth: depth, string: true, escaped: false },
+                        b'{' | b'[' => Phase::Value { depth: depth + 1, string: false, escaped: false },
+                        b'}' | b']' if depth > 0 =>
+                            Phase::Value { depth: depth - 1, string: false, escaped: false },
+                        b',' | b'}' | b']' if depth == 0 => {
+                            // Ends the value, and is looked at again.
+                            self.phase = Phase::AfterValue;
+                            continue;
+                        }
+                        _ if whitespace && depth == 0 => Phase::AfterValue,
+                        _ => phase,
+                    }
+                }
+
+                Phase::AfterValue if whitespace => phase,
+                Phase::AfterValue if current == b',' => Phase::Key,
+                Phase::AfterValue if current == b'}' => return LookaheadResult::Missing,
+                Phase::AfterValue => return LookaheadResult::Invalid,
+            };
+            self.position += 1;
+        }
+    }
+
+}
@@ -4,14 +4,17 @@ use ::rustler::{
     NifEncoder,
 };
 
-use ::num_traits::Num;
-use ::num_bigint::BigUint;
+use ::num_traits::{Num, FromPrimitive};
+use ::num_bigint::{BigInt, BigUint, Sign};
 
 use ::std::str::FromStr;
+use ::std::cmp::Ordering;
 
 use ::iterative_json_parser::{NumberData, Range};
 
-fn integer_to_bigint_term<'a>(env: NifEnv<'a>, sign: bool, number: &str) -> NifTerm<'a> {
+use ::tree_spec::NumberBound;
+
+fn integer_to_bigint_term<'a>(env: NifEnv<'a>, sign: bool, number: &str) -> Option<NifTerm<'a>> {
     // http://erlang.org/doc/apps/erts/erl_ext_dist.html#id101259
 
     let num = BigUint::from_str_radix(number, 10).unwrap();
@@ -35,31 +38,36 @@ fn integer_to_bigint_term<'a>(env: NifEnv<'a>, sign: bool, number: &str) -> NifT
     buf.extend_from_slice(&bytes_le);
 
     // This is safe because we manually constructed the data, and we
-    // are completely sure that it is valid.
-    let (term, _) = unsafe { env.binary_to_term_trusted(&buf) }.unwrap();
-    term
+    // are completely sure that it is valid. It still fails if the number is
+    // larger than the VM supports.
+    unsafe { env.binary_to_term_trusted(&buf) }.map(|(term, _)| term)
 }
 
-fn integer_to_term<'a>(env: NifEnv<'a>, sign: bool, num_str: &str) -> NifTerm<'a> {
+fn integer_to_term<'a>(env: NifEnv<'a>, sign: bool, num_str: &str) -> Option<NifTerm<'a>> {
     if sign {
         match u64::from_str(num_str) {
-            Ok(number) => number.encode(env),
+            Ok(number) => Some(number.encode(env)),
             Err(_) => integer_to_bigint_term(env, sign, num_str),
         }
     } else {
         match i64::from_str(num_str) {
-            Ok(number) => (-number).encode(env),
+            Ok(number) => Some((-number).encode(env)),
             Err(_) => integer_to_bigint_term(env, sign, num_str),
         }
     }
 }
 
-fn float_to_term<'a>(env: NifEnv<'a>, num_str: &str) -> NifTerm<'a> {
-    let number = f64::from_str(num_str).ok().unwrap();
-    number.encode(env)
+fn float_to_term<'a>(env: NifEnv<'a>, num_str: &str) -> Option<NifTerm<'a>> {
+    // Floats that overflow parse as infinity, which the VM can't represent.
+    match f64::from_str(num_str) {
+        Ok(number) if number.is_finite() => Some(number.encode(env)),
+        _ => None,
+    }
 }
 
-pub fn number_data_to_term<'a, F>(env: NifEnv<'a>, data: NumberData, range_provider: F) -> NifTerm<'a>
+/// Makes a term for the number. Returns `None` if the number is out of the
+/// range the VM can represent.
+pub fn number_data_to_term<'a, F>(env: NifEnv<'a>, data: NumberData, range_provider: F) -> Option<NifTerm<'a>>
     where F: Fn(Range, &mut Vec<u8>) {
 
     // TODO: Do not allocate
@@ -75,21 +83,7 @@ pub fn number_data_to_term<'a, F>(env: NifEnv<'a>, data: NumberData, range_provi
             integer_to_term(env, data.sign, num_str)
         }
         _ => {
-            if !data.sign {
-                buf.push('-' as u8);
-            }
-            range_provider(data.integer, &mut buf);
-            if let Some(decimal) = data.decimal {
-                buf.push('.' as u8);
-                range_provider(decimal, &mut buf);
-            }
-            if let Some(exponent) = data.exponent {
-                buf.push('e' as u8);
-                if !data.exponent_sign {
-                    buf.push('-' as u8);
-                }
-                range_provider(exponent, &mut buf);
-            }
+            push_float_str(&data, &range_provider, &mut buf);
 
             // This is safe because the tokenizer only accepts digits when reading numbers.
             // This range will thus never contain anything other than 0..9 + the symbols
@@ -100,3 +94,99 @@ pub fn number_data_to_term<'a, F>(env: NifEnv<'a>, data: NumberData, range_provi
         }
     }
 }
+
+fn push_float_str<F>(data: &NumberData, range_provider: &F, buf: &mut Vec<u8>)
+    where F: Fn(Range, &mut Vec<u8>) {
+
+    if !data.sign {
+        buf.push('-' as u8);
+    }
+    range_provider(data.integer, buf);
+    if let Some(decimal) = data.decimal {
+        buf.push('.' as u8);
+        range_provider(decimal, buf);
+    }
+    if let Some(exponent) = data.exponent {
+        buf.push('e' as u8);
+        if !data.exponent_sign {
+            buf.push('-' as u8);
+        }
+        range_provider(exponent, buf);
+    }
+}
+
+/// Reads the number as a float, used when checking it against constraints.
+pub fn number_data_to_f64<F>(data: &NumberData, range_provider: F) -> f64
+    where F: Fn(Range, &mut Vec<u8>) {
+
+    let mut buf = Vec::<u8>::new();
+    push_float_str(data, &range_provider, &mut buf);
+
+    // Safe for the same reason as above.
+    let num_str = unsafe { ::std::str::from_utf8_unchecked(&buf) };
+    f64::from_str(num_str).ok().unwrap()
+}
+
+/// Makes a term for an integer of any size.
+pub fn bigint_to_term<'a>(env: NifEnv<'a>, number: &BigInt) -> NifTerm<'a> {
+    let number_str = number.to_str_radix(10);
+    let term = if number_str.starts_with('-') {
+        integer_to_term(env, false, &number_str[1..])
+    } else {
+        integer_to_term(env, true, &number_str)
+    };
+    // Only integers the VM could represent get here.
+    term.unwrap()
+}
+
+/// Converts an integer of any size to the nearest float.
+pub fn bigint_to_f64(number: &BigInt) -> f64 {
+    f64::from_str(&number.to_str_radix(10)).unwrap()
+}
+
+/// Reads an integer term of any size. Integers that don't fit in 64 bits are
+/// read from the external term format.
+pub fn term_to_bigint<'a>(term: NifTerm<'a>) -> Option<BigInt> {
+    if let Ok(integer) = term.decode::<i64>() {
+        return BigInt::from_i64(integer);
+    }
+    let binary = term.to_binary();
+    let bytes = binary.as_slice();
+    // After the magic byte, small and large bignum tags.
+    let (length, rest) = match bytes.get(1) {
+        Some(&110) if bytes.len() >= 3 => (bytes[2] as usize, &bytes[3..]),
+        Some(&111) if bytes.len() >= 6 => {
+            let length = bytes[2..6].iter().fold(0, |length, &byte| (length << 8) | byte as usize);
+            (length, &bytes[6..])
+        }
+        _ => return None,
+    };
+    if rest.len() != length + 1 {
+        return None;
+    }
+    let sign = if rest[0] == 0 { Sign::Plus } else { Sign::Minus };
+    Some(BigInt::from_biguint(sign, BigUint::from_bytes_le(&rest[1..])))
+}
+
+/// Compares the number with a bound from a spec. Integers are compared
+/// exactly with integer bounds, as floats only when either side is a float.
+pub fn compare_number_data<F>(data: &NumberData, bound: &NumberBound, range_provider: F) -> Ordering
+    where F: Fn(Range, &mut Vec<u8>) {
+
+    let is_integer = data.decimal.is_none() && data.exponent.is_none();
+    match *bound {
+        NumberBound::Integer(ref bound) if is_integer => {
+            let mut buf = Vec::<u8>::new();
+            range_provider(data.integer, &mut buf);
+            // Safe for the same reason as above.
+            let num_str = unsafe { ::std::str::from_utf8_unchecked(&buf) };
+            let magnitude = BigUint::from_str_radix(num_str, 10).unwrap();
+            let sign = if data.sign { Sign::Plus } else { Sign::Minus };
+            BigInt::from_biguint(sign, magnitude).cmp(bound)
+        }
+        _ => {
+            let value = number_data_to_f64(data, range_provider);
+            value.partial_cmp(&bound.to_f64()).unwrap_or(Ordering::Equal)
+        }
+    }
+}
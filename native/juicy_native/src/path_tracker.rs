@@ -1,55 +1,246 @@
-use ::tree_spec::{SpecWalker, PathEntry, ValueType, PathPosition, NodeId};
+use ::tree_spec::{Spec, NodeId, SpecWalker, PathEntry, ValueType, PathPosition, KeyPosition, KeyAction};
 use ::iterative_json_parser::{Position};
 
+use std::sync::Arc;
+use std::collections::HashSet;
+
+/// State for a map we are currently inside of.
+struct MapFrame {
+    /// Whether a key for this map is on the path.
+    has_key: bool,
+    /// Whether the current key should be left out of the result, together
+    /// with its value.
+    key_ignored: bool,
+    /// Keys that have been left behind in this map. Only the ones that are
+    /// required or have defaults are tracked.
+    seen: HashSet<Vec<u8>>,
+}
+
+/// Keeps track of the path to the value currently being parsed.
+///
+/// Entries on the path are updated when the next sibling is visited, not
+/// when the current one is completed. This means the path is correct both
+/// while a value is being parsed, and right after it has been completed.
 pub struct PathTracker {
     pub path: Vec<PathEntry>,
     pub walker: SpecWalker,
+    maps: Vec<MapFrame>,
+    /// For every array we are in, whether the current element should be
+    /// left out of the result.
+    arrays: Vec<bool>,
+    /// When not 0, we are inside of a subtree whose output should not be
+    /// built. Counts how many nonterminals deep into that subtree we are.
+    skip_depth: usize,
 }
 
 impl PathTracker {
 
+    pub fn new(spec: Arc<Spec>) -> PathTracker {
+        PathTracker {
+            path: Vec::new(),
+            walker: SpecWalker::new(spec),
+            maps: Vec::new(),
+            arrays: Vec::new(),
+            skip_depth: 0,
+        }
+    }
+
     pub fn visit_terminal(&mut self, _pos: Position, typ: ValueType) -> PathPosition {
+        self.advance_index();
         let path_pos = self.walker.visit_terminal(typ, self.path.last());
-        self.update_path();
+        self.mark_dropped(&path_pos);
         path_pos
     }
 
-    pub fn enter_array(&mut self, _pos: Position) {
-        {
-            let last_key = self.path.last();
-            self.walker.enter_nonterminal(ValueType::Array, last_key);
-        }
-        self.path.push(PathEntry::Index(0));
+    pub fn enter_array(&mut self, _pos: Position) -> PathPosition {
+        let path_pos = self.enter_nonterminal(ValueType::Array);
+        self.path.push(PathEntry::Index(None));
+        self.arrays.push(false);
+        path_pos
     }
 
-    pub fn enter_map(&mut self, _pos: Position) {
-        let last_key = self.path.last();
-        self.walker.enter_nonterminal(ValueType::Object, last_key);
+    pub fn enter_map(&mut self, _pos: Position) -> PathPosition {
+        let path_pos = self.enter_nonterminal(ValueType::Object);
+        self.maps.push(MapFrame {
+            has_key: false,
+            key_ignored: false,
+            seen: HashSet::new(),
+        });
+        path_pos
+    }
+
+    /// Number of maps and arrays we are in.
+    pub fn depth(&self) -> usize {
+        self.maps.len() + self.arrays.len()
+    }
+
+    /// Number of elements visited so far in the array we are in.
+    pub fn array_length(&self) -> usize {
+        match self.path.last() {
+            Some(&PathEntry::Index(index)) => index.map_or(0, |index| index + 1),
+            _ => unreachable!(),
+        }
     }
 
     pub fn exit_array(&mut self) -> PathPosition {
         self.path.pop().unwrap();
-        let path_pos = self.walker.exit_nonterminal();
-        self.update_path();
-        path_pos
+        self.arrays.pop().unwrap();
+        self.exit_nonterminal()
     }
 
     pub fn exit_map(&mut self) -> PathPosition {
-        let path_pos = self.walker.exit_nonterminal();
-        self.update_path();
-        path_pos
+        let frame = self.maps.pop().unwrap();
+        if frame.has_key {
+            self.path.pop().unwrap();
+        }
+        self.exit_nonterminal()
     }
 
-    pub fn enter_key(&mut self, key: Vec<u8>) -> Option<NodeId> {
+    pub fn enter_key(&mut self, key: Vec<u8>) -> KeyPosition {
+        let key_pos = self.walker.visit_key(&key);
+        let options = match key_pos.parent {
+            Some(node_id) => Some(&self.walker.spec.get(node_id).options),
+            None => None,
+        };
+
+        let frame = self.maps.last_mut().unwrap();
+        if frame.has_key {
+            if let PathEntry::Key(previous) = self.path.pop().unwrap() {
+                if options.map_or(false, |options| options.tracks_key(&previous)) {
+                    frame.seen.insert(previous);
+                }
+            }
+        }
+        frame.has_key = true;
+        frame.key_ignored = key_pos.action == KeyAction::Ignore;
+
         self.path.push(PathEntry::Key(key));
-        self.walker.visit_key()
+        key_pos
+    }
+
+    /// Whether the key has been seen in the map we are in. Only valid for
+    /// nodes that track keys, and before the map is exited.
+    pub fn key_seen(&self, key: &[u8]) -> bool {
+        let frame = self.maps.last().unwrap();
+        if frame.has_key && self.path.last().unwrap().key() == key {
+            return true;
+        }
+        frame.seen.contains(key)
+    }
+
+    /// The first required key of the map we are in that has not been seen,
+    /// and has no default. Must be called before the map is exited.
+    pub fn missing_key(&self) -> Option<&[u8]> {
+        let options = match self.walker.current() {
+            Some(node_id) => &self.walker.spec.get(node_id).options,
+            None => return None,
+        };
+
+        options.required.iter()
+            .flat_map(|required| required.iter())
+            .map(|key| &key[..])
+            .find(|&key| !self.key_seen(key) && !options.has_default(key))
+    }
+
+    /// Moves the nonterminal we just entered over to another node, once a
+    /// matcher has decided what node it should be parsed as.
+    ///
+    /// Returns true if output should no longer be built for it, the caller
+    /// is then responsible for dropping what it has already built.
+    pub fn replace_current(&mut self, node: NodeId, typ: ValueType) -> bool {
+        self.walker.replace_current(node, typ);
+        let current = self.walker.current().unwrap();
+        if self.skip_depth == 0 && self.walker.spec.get(current).variant.skips_output() {
+            self.skip_depth = 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the output for the current value should not be built.
+    ///
+    /// This includes values that are left out of the map or array they are
+    /// in, so it also tells whether the value that was just completed should
+    /// be put into its parent.
+    pub fn skipping(&self) -> bool {
+        self.skip_depth > 0 || self.value_dropped()
+    }
+
+    /// Whether the value that was just completed should be thrown away
+    /// rather than put into the map or array we are in.
+    pub fn discarding(&self) -> bool {
+        match self.walker.current() {
+            Some(node_id) => self.walker.spec.get(node_id).variant.discards_children(),
+            None => false,
+        }
+    }
+
+    /// Whether we are inside of a nonterminal whose output should not be
+    /// built.
+    pub fn in_skipped_subtree(&self) -> bool {
+        self.skip_depth > 0
+    }
+
+    /// Whether the current value of the map or array we are in should be
+    /// left out of it.
+    fn value_dropped(&self) -> bool {
+        // Arrays always have an index on the path. A map that has not seen a
+        // key yet still has the entry of its parent on the path, whether
+        // that was dropped is covered by `skip_depth`.
+        match self.path.last() {
+            Some(&PathEntry::Key(_)) => {
+                let frame = self.maps.last().unwrap();
+                frame.has_key && frame.key_ignored
+            }
+            Some(&PathEntry::Index(_)) => *self.arrays.last().unwrap(),
+            None => false,
+        }
+    }
+
+    /// Values in maps are dropped depending on their key, elements of arrays
+    /// are dropped depending on the node they were matched against.
+    fn mark_dropped(&mut self, path_pos: &PathPosition) {
+        if let Some(&PathEntry::Index(_)) = self.path.last() {
+            let dropped = match path_pos.current {
+                Some(node_id) => self.walker.spec.get(node_id).variant.drops_value(),
+                None => false,
+            };
+            *self.arrays.last_mut().unwrap() = dropped;
+        }
+    }
+
+    fn enter_nonterminal(&mut self, typ: ValueType) -> PathPosition {
+        self.advance_index();
+        let path_pos = {
+            let last_key = self.path.last();
+            self.walker.enter_nonterminal(typ, last_key)
+        };
+        self.mark_dropped(&path_pos);
+
+        let skip_node = match path_pos.current {
+            Some(node_id) => self.walker.spec.get(node_id).variant.skips_output(),
+            None => false,
+        };
+        if self.skipping() || skip_node {
+            self.skip_depth += 1;
+        }
+
+        path_pos
+    }
+
+    fn exit_nonterminal(&mut self) -> PathPosition {
+        if self.skip_depth > 0 {
+            self.skip_depth -= 1;
+        }
+        self.walker.exit_nonterminal()
     }
 
-    fn update_path(&mut self) {
-        match self.path.pop() {
-            Some(PathEntry::Index(index)) => self.path.push(PathEntry::Index(index + 1)),
-            Some(_) => (),
-            None => (),
+    /// Moves the index of the array we are in to the element we are about
+    /// to visit.
+    fn advance_index(&mut self) {
+        if let Some(&mut PathEntry::Index(ref mut index)) = self.path.last_mut() {
+            *index = Some(index.map_or(0, |index| index + 1));
         }
     }
 
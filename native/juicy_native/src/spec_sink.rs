@@ -0,0 +1,384 @@
+//! Checks and values shared by the sinks that parse with a spec.
+//!
+//! The sinks only differ in where their input comes from and how failures
+//! are reported, so these take the path tracker and the input, and leave
+//! reporting a `SpecFailure` to the sink.
+
+use std::cmp::Ordering;
+
+use rustler::{NifEnv, NifTerm, NifEncoder};
+use rustler::types::atom::NifAtom;
+use rustler::types::binary::NifBinary;
+use rustler::types::tuple::make_tuple;
+
+use ::iterative_json_parser::NumberData;
+
+use ::input_provider::InputProvider;
+use ::lookahead::{KeyScan, LookaheadResult};
+use ::numbers::{number_data_to_term, number_data_to_f64, compare_number_data};
+use ::path_tracker::PathTracker;
+use ::strings::{BuildString, bytes_to_term};
+use ::tree_spec::{ValueType, KeyAction, KeyPosition, Node, NodeId, NodeVariant, PathPosition,
+                  LengthUnit, NumberBound, resolve_tag};
+
+/// A value that does not satisfy the spec.
+pub struct SpecFailure<'a> {
+    pub reason: NifAtom,
+    pub detail: NifTerm<'a>,
+    /// How many entries of the current path lead to the value.
+    pub path_len: usize,
+    /// Where in the input the value failed, if not at the current position.
+    pub position: Option<usize>,
+}
+
+impl<'a> SpecFailure<'a> {
+
+    /// Fails the value at the current path.
+    pub fn new(tracker: &PathTracker, reason: NifAtom, detail: NifTerm<'a>) -> SpecFailure<'a> {
+        SpecFailure {
+            reason: reason,
+            detail: detail,
+            path_len: tracker.path.len(),
+            position: None,
+        }
+    }
+
+    /// Fails for a state the spec should not be able to get us into.
+    pub fn internal(env: NifEnv<'a>, tracker: &PathTracker) -> SpecFailure<'a> {
+        SpecFailure::new(tracker, ::atoms::internal_error(), ::atoms::nil().encode(env))
+    }
+
+    /// Fails the map or array the current path entry is in instead.
+    fn in_parent(mut self) -> SpecFailure<'a> {
+        self.path_len -= 1;
+        self
+    }
+
+}
+
+fn spec_node<'t>(tracker: &'t PathTracker, node_id: Option<NodeId>) -> Option<&'t Node> {
+    node_id.map(|id| tracker.walker.spec.get(id))
+}
+
+/// The term to use in place of the value when the node is static.
+pub fn static_value<'a>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>)
+                        -> Option<NifTerm<'a>> {
+    match spec_node(tracker, node_id) {
+        Some(&Node { variant: NodeVariant::Static { ref value }, .. }) =>
+            Some(env.binary_to_term(value).unwrap().0),
+        _ => None,
+    }
+}
+
+/// Fails if the value we just visited did not match what the spec
+/// expected, in strict mode or for nodes that always reject mismatches.
+pub fn check_match<'a>(env: NifEnv<'a>, tracker: &PathTracker, path_pos: &PathPosition,
+                       typ: ValueType) -> Result<(), SpecFailure<'a>> {
+    let expected = match spec_node(tracker, path_pos.mismatch) {
+        Some(node) if node.options.strict || node.variant.rejects_mismatch() =>
+            node.variant.expected_types(),
+        _ => return Ok(()),
+    };
+    let expected_term = if expected.len() == 1 {
+        expected[0].encode(env)
+    } else {
+        expected.encode(env)
+    };
+
+    let failure = SpecFailure::new(tracker, ::atoms::spec_mismatch(), (expected_term, typ).encode(env));
+    match typ {
+        // Arrays we just entered have their index on the path.
+        ValueType::Array => Err(failure.in_parent()),
+        _ => Err(failure),
+    }
+}
+
+/// Fails if the string we just parsed is not valid UTF-8.
+pub fn check_utf8<'a, T, M>(env: NifEnv<'a>, tracker: &PathTracker, string: &BuildString, input: &T)
+                            -> Result<(), SpecFailure<'a>> where T: InputProvider<M> {
+    string.check_utf8(input).map_err(|invalid_pos| {
+        let mut failure = SpecFailure::new(tracker, ::atoms::invalid_utf8(), ::atoms::nil().encode(env));
+        failure.position = invalid_pos;
+        failure
+    })
+}
+
+/// Fails if the key we just entered is not valid UTF-8, or not allowed by
+/// the map it is in.
+pub fn check_key<'a>(env: NifEnv<'a>, tracker: &PathTracker, key_pos: &KeyPosition)
+                     -> Result<(), SpecFailure<'a>> {
+    let nil = ::atoms::nil().encode(env);
+    let key = tracker.path.last().unwrap().key();
+    if ::std::str::from_utf8(key).is_err() {
+        return Err(SpecFailure::new(tracker, ::atoms::invalid_utf8(), nil));
+    }
+    match key_pos.action {
+        KeyAction::Error => Err(SpecFailure::new(tracker, ::atoms::unknown_key(), nil)),
+        KeyAction::Mismatch => {
+            let known: Vec<NifTerm> = match spec_node(tracker, key_pos.parent) {
+                Some(node) => node.variant.known_keys().iter()
+                    .map(|known_key| bytes_to_term(env, known_key))
+                    .collect(),
+                None => Vec::new(),
+            };
+            let detail = ((::atoms::keys(), known), (::atoms::key(), bytes_to_term(env, key)));
+            Err(SpecFailure::new(tracker, ::atoms::spec_mismatch(), detail.encode(env)).in_parent())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The term for the key we just entered, an atom if the map maps it to
+/// one.
+pub fn key_term<'a>(env: NifEnv<'a>, tracker: &PathTracker, key_pos: &KeyPosition) -> NifTerm<'a> {
+    let key = tracker.path.last().unwrap().key();
+    let atom = match spec_node(tracker, key_pos.parent) {
+        Some(node) => node.options.atom_mappings.as_ref().and_then(|mappings| mappings.get(key)),
+        None => None,
+    };
+    match atom {
+        Some(atom) => atom.encode(env),
+        None => bytes_to_term(env, key),
+    }
+}
+
+/// Keys and values for the defaults of the map we are in, for the keys
+/// that were absent from it. Must be called before the map is exited.
+pub fn absent_defaults<'a>(env: NifEnv<'a>, tracker: &PathTracker) -> Vec<(NifTerm<'a>, NifTerm<'a>)> {
+    let node = match tracker.walker.current() {
+        Some(node_id) => tracker.walker.spec.get(node_id),
+        None => return Vec::new(),
+    };
+
+    node.options.defaults.iter()
+        .filter(|&&(ref key, _)| !tracker.key_seen(key))
+        .map(|&(ref key, ref value)| {
+            let key_term = match node.options.atom_mappings.as_ref().and_then(|mappings| mappings.get(key)) {
+                Some(atom) => atom.encode(env),
+                None => bytes_to_term(env, key),
+            };
+            (key_term, env.binary_to_term(value).unwrap().0)
+        })
+        .collect()
+}
+
+/// Finishes the term for a map we just exited, with the defaults of
+/// absent keys and the struct it is made into.
+pub fn map_value<'a>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>,
+                     mut term: NifTerm<'a>, defaults: Vec<(NifTerm<'a>, NifTerm<'a>)>) -> NifTerm<'a> {
+    for (key, value) in defaults {
+        term = term.map_put(key, value).ok().unwrap();
+    }
+    match spec_node(tracker, node_id).and_then(|node| node.options.struct_atom.as_ref()) {
+        Some(atom) => term.map_put(::atoms::__struct__().encode(env), atom.encode(env)).ok().unwrap(),
+        None => term,
+    }
+}
+
+/// Finishes the term for an array we just exited, checking the length of
+/// tuples and the tag of tag arrays.
+pub fn array_value<'a>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>,
+                       length: usize, term: NifTerm<'a>) -> Result<NifTerm<'a>, SpecFailure<'a>> {
+    let term = term.list_reverse().ok().unwrap();
+
+    let (expected, as_tuple, tag_required) = match spec_node(tracker, node_id) {
+        Some(&Node { variant: NodeVariant::Tuple { ref children }, ref options, .. }) =>
+            (children.len(), options.as_tuple, false),
+        // Without a default, the value is only matched when the tag is a
+        // string.
+        Some(&Node { ref variant @ NodeVariant::TagArray { .. }, ref options, .. }) =>
+            (2, options.as_tuple, resolve_tag(variant, None) == Ok(None)),
+        _ => return Ok(term),
+    };
+
+    if expected != length {
+        let detail = (expected as u64, length as u64);
+        return Err(SpecFailure::new(tracker, ::atoms::tuple_length(), detail.encode(env)));
+    }
+    let elements: Vec<NifTerm> = term.decode().ok().unwrap();
+    if tag_required && elements[0].decode::<NifBinary>().is_err() {
+        return Err(SpecFailure::new(tracker, ::atoms::missing_tag(), ::atoms::nil().encode(env)));
+    }
+    if as_tuple {
+        Ok(make_tuple(env, &elements))
+    } else {
+        Ok(term)
+    }
+}
+
+/// The unit the length of a string value is counted in, if its node has
+/// length constraints.
+pub fn string_length_unit(tracker: &PathTracker, string_position: Option<PathPosition>)
+                          -> Option<LengthUnit> {
+    string_position
+        .and_then(|path_pos| spec_node(tracker, path_pos.current))
+        .and_then(|node| node.options.counted_length())
+}
+
+/// Fails if a string value is already longer than its node allows.
+pub fn check_max_length<'a>(env: NifEnv<'a>, tracker: &PathTracker,
+                            string_position: Option<PathPosition>, length: usize)
+                            -> Result<(), SpecFailure<'a>> {
+    let max_length = string_position
+        .and_then(|path_pos| spec_node(tracker, path_pos.current))
+        .and_then(|node| node.options.max_length);
+    match max_length {
+        Some(max_length) if length > max_length =>
+            Err(SpecFailure::new(tracker, ::atoms::string_too_long(), (max_length as u64).encode(env))),
+        _ => Ok(()),
+    }
+}
+
+/// Fails if the number does not satisfy the constraints of its node.
+fn check_number<'a, T, M>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>,
+                          num: &NumberData, input: &T) -> Result<(), SpecFailure<'a>>
+    where T: InputProvider<M> {
+
+    let options = match spec_node(tracker, node_id) {
+        Some(node) => &node.options,
+        None => return Ok(()),
+    };
+    if !options.constrains_numbers() {
+        return Ok(());
+    }
+
+    let value = number_data_to_f64(num, |r, b| input.push_range(r, b));
+    let is_integer = num.decimal.is_none() && num.exponent.is_none();
+
+    let reason = if options.integer_only && !is_integer {
+        ::atoms::not_integer().encode(env)
+    } else if options.non_negative && value < 0.0 {
+        ::atoms::negative().encode(env)
+    } else {
+        let compare = |bound: &NumberBound| {
+            compare_number_data(num, bound, |r, b| input.push_range(r, b))
+        };
+        match (&options.min, &options.max) {
+            (&Some(ref min), _) if compare(min) == Ordering::Less =>
+                (::atoms::min(), min.encode(env)).encode(env),
+            (_, &Some(ref max)) if compare(max) == Ordering::Greater =>
+                (::atoms::max(), max.encode(env)).encode(env),
+            _ => return Ok(()),
+        }
+    };
+    Err(SpecFailure::new(tracker, ::atoms::invalid_number(), reason))
+}
+
+/// Makes the term for a number value, applying the node it was matched
+/// against.
+pub fn number_value<'a, T, M>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>,
+                              num: NumberData, input: &T) -> Result<NifTerm<'a>, SpecFailure<'a>>
+    where T: InputProvider<M> {
+
+    check_number(env, tracker, node_id, &num, input)?;
+    if let Some(term) = static_value(env, tracker, node_id) {
+        return Ok(term);
+    }
+    match number_data_to_term(env, num, |r, b| input.push_range(r, b)) {
+        Some(term) => Ok(term),
+        None => {
+            let detail = ::atoms::out_of_range().encode(env);
+            Err(SpecFailure::new(tracker, ::atoms::invalid_number(), detail))
+        }
+    }
+}
+
+/// Makes the term for a string value, applying the node it was matched
+/// against. `length` is only counted for nodes with length constraints.
+pub fn string_value<'a, T, M>(env: NifEnv<'a>, tracker: &PathTracker, node_id: Option<NodeId>,
+                              string: BuildString, length: usize, input: &T)
+                              -> Result<NifTerm<'a>, SpecFailure<'a>>
+    where T: InputProvider<M> {
+
+    let node = spec_node(tracker, node_id);
+    if let Some(min_length) = node.and_then(|node| node.options.min_length) {
+        if length < min_length {
+            let detail = (min_length as u64, length as u64);
+            return Err(SpecFailure::new(tracker, ::atoms::string_too_short(), detail.encode(env)));
+        }
+    }
+
+    match node {
+        Some(&Node { variant: NodeVariant::Static { ref value }, .. }) =>
+            Ok(env.binary_to_term(value).unwrap().0),
+        Some(&Node { variant: NodeVariant::Enum { ref values }, .. }) =>
+            match values.get(&string.to_vec(input)) {
+                Some(atom) => Ok(atom.encode(env)),
+                None => {
+                    let value = string.to_term(input, env);
+                    Err(SpecFailure::new(tracker, ::atoms::invalid_enum(), value))
+                }
+            },
+        _ => Ok(string.to_term(input, env)),
+    }
+}
+
+pub fn is_tagged_map(tracker: &PathTracker, node_id: Option<NodeId>) -> bool {
+    match spec_node(tracker, node_id) {
+        Some(&Node { variant: NodeVariant::TaggedMap { .. }, .. }) => true,
+        _ => false,
+    }
+}
+
+/// Goes on looking ahead for the tag of the tagged map we are in, or
+/// returns `None` if we are not in one.
+pub fn scan_tag<F>(tracker: &PathTracker, scan: &mut KeyScan, byte: F, budget: usize)
+                   -> Option<LookaheadResult> where F: Fn(usize) -> Option<u8> {
+    let node_id = match tracker.walker.current() {
+        Some(node_id) => node_id,
+        None => return None,
+    };
+    match tracker.walker.spec.get(node_id).variant {
+        NodeVariant::TaggedMap { ref key, .. } => Some(scan.run(byte, key, budget)),
+        _ => None,
+    }
+}
+
+/// Decides what node the tagged map we are in should be parsed as, given
+/// the tag found for it.
+pub fn resolve_map_tag<'a>(env: NifEnv<'a>, tracker: &PathTracker, tag: Option<Vec<u8>>)
+                           -> Result<NodeId, SpecFailure<'a>> {
+    let resolved = match tracker.walker.current() {
+        Some(node_id) => resolve_tag(&tracker.walker.spec.get(node_id).variant,
+                                     tag.as_ref().map(|tag| &tag[..])),
+        None => Err(()),
+    };
+    match (resolved, tag) {
+        (Ok(Some(node_id)), _) => Ok(node_id),
+        (Ok(None), Some(tag)) =>
+            Err(SpecFailure::new(tracker, ::atoms::unknown_tag(), bytes_to_term(env, &tag))),
+        (Ok(None), None) =>
+            Err(SpecFailure::new(tracker, ::atoms::missing_tag(), ::atoms::nil().encode(env))),
+        (Err(()), _) => Err(SpecFailure::internal(env, tracker)),
+    }
+}
+
+/// Whether the value we just visited is the tag of a tag array.
+pub fn is_array_tag(tracker: &PathTracker, parent_id: Option<NodeId>) -> bool {
+    match spec_node(tracker, parent_id) {
+        Some(&Node { variant: NodeVariant::TagArray { .. }, .. }) => tracker.array_length() == 1,
+        _ => false,
+    }
+}
+
+/// Selects the node for the value of the tag array we are in, given the
+/// tag we just read.
+pub fn select_array_tag<'a, T, M>(env: NifEnv<'a>, tracker: &mut PathTracker, tag: &BuildString,
+                                  input: &T) -> Result<(), SpecFailure<'a>>
+    where T: InputProvider<M> {
+
+    let tag = tag.to_vec(input);
+    let resolved = match tracker.walker.current() {
+        Some(node_id) => resolve_tag(&tracker.walker.spec.get(node_id).variant, Some(&tag[..])),
+        None => Err(()),
+    };
+    match resolved {
+        Ok(Some(node_id)) => {
+            tracker.walker.select_child(node_id);
+            Ok(())
+        }
+        Ok(None) =>
+            Err(SpecFailure::new(tracker, ::atoms::unknown_tag(), bytes_to_term(env, &tag)).in_parent()),
+        Err(()) => Err(SpecFailure::internal(env, tracker)),
+    }
+}
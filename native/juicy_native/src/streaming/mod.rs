@@ -1,41 +1,211 @@
 use iterative_json_parser::{Parser, Pos, ParseError, Unexpected};
 
-use rustler::{NifEnv, NifTerm, NifResult, NifEncoder};
+use rustler::{NifEnv, NifTerm, NifResult, NifEncoder, NifError};
 use rustler::resource::ResourceArc;
 use rustler::types::binary::NifBinary;
 use rustler::types::list::NifListIterator;
 
 use ::strings::BuildString;
 
-use ::tree_spec::spec_from_term;
-use ::tree_spec::SpecWalker;
+use ::tree_spec::read_spec;
 
-use ::input_provider::streaming::StreamingInputProvider;
+use ::input_provider::InputProvider;
+use ::input_provider::streaming::{StreamingInputProvider, StreamingInputResult, HeldInput};
+use ::location::LineCounter;
+use ::errors::{ParseFailure, DEFAULT_MAX_DEPTH, read_max_depth, classify_unexpected, internal_error};
 
 use ::path_tracker::PathTracker;
 
 use std::sync::Mutex;
 use std::ops::DerefMut;
 use std::ops::Range;
+use std::cmp::{min, max};
+use std::mem;
 
 mod source_sink;
 use self::source_sink::{StreamingSS, SSState};
 
+mod atoms {
+    rustler_atoms! {
+        atom format;
+        atom json;
+        atom ndjson;
+        atom json_seq;
+        atom concatenated;
+        atom offsets;
+        atom record;
+        atom path;
+        atom list;
+        atom pointer;
+        atom max_depth;
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum BailType {
     Reschedule,
     AwaitInput,
+    /// The sink failed. The error term is stored in the source sink.
+    Error,
+}
+
+/// Record separator, starts every record in a JSON text sequence.
+pub const RS: u8 = 0x1E;
+
+/// How the input is made up of values.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StreamFormat {
+    /// A single value.
+    Json,
+    /// One value per line. An error only fails the line it is on.
+    Ndjson,
+    /// RFC 7464 JSON text sequence, every value is preceded by `RS`. An
+    /// error only fails the record it is in.
+    JsonSeq,
+    /// Values following each other, separated by whitespace at most. There
+    /// is no way to find the next value after an error.
+    Concatenated,
+}
+
+impl StreamFormat {
+
+    /// Whether the byte ends the value being parsed.
+    pub fn ends_record(self, byte: u8) -> bool {
+        match self {
+            StreamFormat::Ndjson => byte == b'\n',
+            StreamFormat::JsonSeq => byte == RS,
+            _ => false,
+        }
+    }
+
+    /// Whether parsing can continue with the next record after an error.
+    fn recovers(self) -> bool {
+        self == StreamFormat::Ndjson || self == StreamFormat::JsonSeq
+    }
+
 }
 
-fn format_unexpected<'a>(env: NifEnv<'a>, pos: Pos, reason: Unexpected) -> NifTerm<'a> {
-    let position = pos.0 as u64;
-    let explaination = reason.explain().encode(env);
-    (::atoms::error(), (::atoms::unexpected(), position, explaination)).encode(env)
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    pub format: StreamFormat,
+    /// Emit the byte offset of every record as it is started.
+    pub offsets: bool,
+    /// Emit paths as JSON Pointer strings instead of lists.
+    pub pointer_paths: bool,
+    /// Maximum nesting of maps and arrays.
+    pub max_depth: usize,
+}
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            format: StreamFormat::Json,
+            offsets: false,
+            pointer_paths: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+fn read_opts<'a>(term: NifTerm<'a>) -> NifResult<StreamOptions> {
+    let iterator: NifListIterator = term.decode()?;
+    let mut opts = StreamOptions::default();
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::format() == key {
+            opts.format = if atoms::json() == value {
+                StreamFormat::Json
+            } else if atoms::ndjson() == value {
+                StreamFormat::Ndjson
+            } else if atoms::json_seq() == value {
+                StreamFormat::JsonSeq
+            } else if atoms::concatenated() == value {
+                StreamFormat::Concatenated
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::offsets() == key {
+            opts.offsets = value.decode()?;
+        } else if atoms::path() == key {
+            opts.pointer_paths = if atoms::list() == value {
+                false
+            } else if atoms::pointer() == value {
+                true
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::max_depth() == key {
+            opts.max_depth = read_max_depth(value)?;
+        } else {
+            return Err(NifError::BadArg);
+        }
+    }
+    Ok(opts)
+}
+
+/// What we are doing with the input. Every value goes through these in
+/// order.
+#[derive(Copy, Clone)]
+enum Phase {
+    /// Skipping whitespace and separators before the next record.
+    Between,
+    /// Parsing a value.
+    Value,
+    /// After a value, only whitespace may follow until the end of the
+    /// record, or the end of the input for a single value.
+    After,
+    /// Skipping the rest of a record that failed.
+    Recover,
+}
+
+fn format_unexpected<'a, 'b>(env: NifEnv<'a>,
+                             input: &StreamingInputProvider<'a, 'b>,
+                             ss_state: &SSState,
+                             pos: &Pos,
+                             reason: &Unexpected)
+                             -> NifTerm<'a> {
+    // The parser sees the end of a record as the end of the input.
+    let record_end = input.available_byte(pos.0)
+        .map(|byte| ss_state.format.ends_record(byte))
+        .unwrap_or(false);
+    let failure = ParseFailure {
+        reason: if record_end {
+            ::atoms::unexpected_eof()
+        } else {
+            classify_unexpected(|pos| input.available_byte(pos), pos.0, reason)
+        },
+        position: pos.0,
+        path: ss_state.path_term(env),
+        detail: ::atoms::nil().encode(env),
+    };
+    failure.to_term(env, |pos| input.available_byte(pos), &ss_state.lines)
+}
+
+fn format_trailing<'a, 'b>(env: NifEnv<'a>,
+                           input: &StreamingInputProvider<'a, 'b>,
+                           ss_state: &SSState)
+                           -> NifTerm<'a> {
+    let failure = ParseFailure {
+        reason: ::atoms::trailing_data(),
+        position: ss_state.position,
+        path: ss_state.path_term(env),
+        detail: ::atoms::nil().encode(env),
+    };
+    failure.to_term(env, |pos| input.available_byte(pos), &ss_state.lines)
 }
 
 pub struct StreamingIterState {
     parser: Parser,
     ss_state: SSState,
+    phase: Phase,
+    offsets: bool,
+    /// Number of line breaks before the current position, only counted
+    /// between NDJSON records.
+    line: usize,
+    /// Number of records started so far.
+    records: usize,
+    /// Input still needed from binaries that were released.
+    held: HeldInput,
 }
 pub struct StreamingIterStateWrapper(Mutex<StreamingIterState>);
 
@@ -62,22 +232,38 @@ fn write_binaries<'a>(env: NifEnv<'a>,
 }
 
 pub fn parse_init<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
-    let spec = spec_from_term(args[0])?;
+    let spec = read_spec(args[0])?;
+    let opts = read_opts(args[1])?;
 
     let ss_state = SSState {
-        path_tracker: PathTracker {
-            path: Vec::new(),
-            walker: SpecWalker::new(spec),
-        },
+        path_tracker: PathTracker::new(spec),
 
         position: 0,
         first_needed: 0,
         current_string: BuildString::None,
+        tag_lookahead: false,
+        tag_scan: None,
+        max_depth: opts.max_depth,
+        string_position: None,
+        string_length: 0,
+        string_skipped: false,
+        lines: LineCounter::new(),
+        format: opts.format,
+        record: None,
+        pointer_paths: opts.pointer_paths,
     };
 
     let iter_state = StreamingIterState {
         parser: Parser::new(),
         ss_state: ss_state,
+        phase: match opts.format {
+            StreamFormat::Json => Phase::Value,
+            _ => Phase::Between,
+        },
+        offsets: opts.offsets,
+        line: 0,
+        records: 0,
+        held: HeldInput::new(),
     };
 
     let resource = ResourceArc::new(StreamingIterStateWrapper(Mutex::new(iter_state)));
@@ -86,50 +272,272 @@ pub fn parse_init<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTer
     Ok((::atoms::ok(), state).encode(env))
 }
 
+/// Where a scan of the input stopped.
+enum Scan {
+    /// At a byte that was not skipped.
+    Byte(u8),
+    AwaitInput,
+    Eof,
+}
+
+/// Skips bytes for as long as `skip` returns true.
+fn scan<F>(input: &StreamingInputProvider, position: &mut usize, mut skip: F) -> Scan
+    where F: FnMut(u8) -> bool {
+
+    loop {
+        match input.byte(*position) {
+            StreamingInputResult::Ok(byte) => {
+                if !skip(byte) {
+                    return Scan::Byte(byte);
+                }
+                *position += 1;
+            }
+            StreamingInputResult::AwaitInput => return Scan::AwaitInput,
+            StreamingInputResult::Eof => return Scan::Eof,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    match byte {
+        b' ' | b'\t' | b'\n' | b'\r' => true,
+        _ => false,
+    }
+}
+
+/// Whitespace that doesn't end the line.
+fn is_line_whitespace(byte: u8) -> bool {
+    match byte {
+        b' ' | b'\t' | b'\r' => true,
+        _ => false,
+    }
+}
+
 pub fn parse_iter<'a>(env: NifEnv<'a>, args: &[NifTerm<'a>]) -> NifResult<NifTerm<'a>> {
     let binaries_ranges: Vec<(Range<usize>, NifBinary)> = read_binaries(args[0])?;
     let (stack, resource): (Vec<NifTerm<'a>>, ResourceArc<StreamingIterStateWrapper>) =
         args[1].decode()?;
+    // Set once there is no more input coming after these binaries.
+    let finished: bool = args[2].decode()?;
 
-    let (res, out_stack, mut yields, first_needed) = {
-        let mut resource_inner_guard = resource.0.lock().unwrap();
-        let mut iter_state = resource_inner_guard.deref_mut();
-
-        let mut ss = StreamingSS {
-            env: env,
-            input: StreamingInputProvider { binaries: &binaries_ranges },
-            next_reschedule: iter_state.ss_state.position + 40_000,
-            out_stack: stack,
-            state: &mut iter_state.ss_state,
-            yields: Vec::new(),
+    let (status, out_stack, yields, keep_from) = {
+        // Only poisoned if a previous call panicked, the state can't be
+        // trusted after that.
+        let mut resource_inner_guard = match resource.0.lock() {
+            Ok(guard) => guard,
+            Err(_) => return Err(NifError::BadArg),
         };
+        let iter_state = resource_inner_guard.deref_mut();
+        let input = StreamingInputProvider {
+            binaries: &binaries_ranges,
+            held: mem::replace(&mut iter_state.held, HeldInput::new()),
+            finished: finished,
+        };
+        let format = iter_state.ss_state.format;
+        let next_reschedule = iter_state.ss_state.position + 40_000;
 
-        let res = iter_state.parser.run(&mut ss);
-        (res, ss.out_stack, ss.yields, ss.state.first_needed)
-    };
+        let mut out_stack = stack;
+        let mut yields: Vec<NifTerm<'a>> = Vec::new();
 
-    let binaries_out = write_binaries(env, &binaries_ranges, first_needed);
+        let status = loop {
+            if iter_state.ss_state.position >= next_reschedule {
+                break ::atoms::iter();
+            }
 
-    match res {
-        Ok(()) => {
-            yields.push(::atoms::finished().encode(env));
-            let state = (out_stack, resource).encode(env);
-            Ok((::atoms::finished(), yields, binaries_out, state).encode(env))
-        }
-        Err(ParseError::SourceBail(BailType::Reschedule)) => {
-            let state = (out_stack, resource).encode(env);
-            Ok((::atoms::iter(), yields, binaries_out, state).encode(env))
-        }
-        Err(ParseError::SourceBail(BailType::AwaitInput)) => {
-            let state = (out_stack, resource).encode(env);
-            Ok((::atoms::await_input(), yields, binaries_out, state).encode(env))
-        }
-        Err(ParseError::Unexpected(pos, reason)) => {
-            let error = format_unexpected(env, pos, reason);
-            yields.push(error);
-            let state = (out_stack, resource).encode(env);
-            Ok((::atoms::finished(), yields, binaries_out, state).encode(env))
+            match iter_state.phase {
+                Phase::Between => {
+                    let scanned = {
+                        let line = &mut iter_state.line;
+                        scan(&input, &mut iter_state.ss_state.position, |byte| {
+                            if byte == b'\n' {
+                                *line += 1;
+                            }
+                            // Empty records in a sequence are ignored.
+                            is_whitespace(byte) || (format == StreamFormat::JsonSeq && byte == RS)
+                        })
+                    };
+                    iter_state.ss_state.first_needed = iter_state.ss_state.position;
+                    match scanned {
+                        Scan::Byte(_) => {
+                            let record = match format {
+                                StreamFormat::Ndjson => iter_state.line,
+                                _ => iter_state.records,
+                            };
+                            iter_state.records += 1;
+                            if iter_state.offsets {
+                                let offset = iter_state.ss_state.position as u64;
+                                yields.push((atoms::record(), record as u64, offset).encode(env));
+                            }
+                            iter_state.parser = Parser::new();
+                            iter_state.ss_state.start_record(record);
+                            iter_state.phase = Phase::Value;
+                        }
+                        Scan::AwaitInput => break ::atoms::await_input(),
+                        Scan::Eof => {
+                            yields.push(::atoms::finished().encode(env));
+                            break ::atoms::finished();
+                        }
+                    }
+                }
+
+                Phase::Value => {
+                    let (res, mut error) = {
+                        let mut ss = StreamingSS {
+                            env: env,
+                            input: &input,
+                            next_reschedule: next_reschedule,
+                            out_stack: out_stack,
+                            state: &mut iter_state.ss_state,
+                            yields: yields,
+                            error: None,
+                        };
+                        let res = iter_state.parser.run(&mut ss);
+                        out_stack = ss.out_stack;
+                        yields = ss.yields;
+                        (res, ss.error)
+                    };
+
+                    if error.is_none() {
+                        error = match res {
+                            Ok(()) => None,
+                            Err(ParseError::Unexpected(ref pos, ref reason)) =>
+                                Some(format_unexpected(env, &input, &iter_state.ss_state, pos, reason)),
+                            Err(ParseError::SourceBail(BailType::Reschedule)) => None,
+                            Err(ParseError::SourceBail(BailType::AwaitInput)) => None,
+                            Err(_) => {
+                                let failure = internal_error(env, iter_state.ss_state.position);
+                                let lines = &iter_state.ss_state.lines;
+                                Some(failure.to_term(env, |pos| input.available_byte(pos), lines))
+                            }
+                        };
+                    }
+
+                    if let Some(error) = error {
+                        yields.push(error);
+                        if !format.recovers() {
+                            break ::atoms::finished();
+                        }
+                        out_stack.clear();
+                        iter_state.phase = Phase::Recover;
+                        continue;
+                    }
+
+                    match res {
+                        Ok(()) => {
+                            if iter_state.ss_state.record.is_some() {
+                                // Records are yielded whole, unless the spec
+                                // already streamed the value, or only streams
+                                // values from inside of it.
+                                let streamed = {
+                                    let root = iter_state.ss_state.path_tracker.walker.spec.root_node();
+                                    root.options.stream || root.variant.discards_children()
+                                };
+                                let value = out_stack.pop();
+                                if let (false, Some(value)) = (streamed, value) {
+                                    // Back at the root, this is the path to
+                                    // the record.
+                                    let path = iter_state.ss_state.path_term(env);
+                                    yields.push((::atoms::yield_(), (path, value)).encode(env));
+                                }
+                            }
+                            iter_state.phase = Phase::After;
+                        }
+                        Err(ParseError::SourceBail(BailType::AwaitInput)) =>
+                            break ::atoms::await_input(),
+                        // Only rescheduling is left, everything else was
+                        // reported as an error above.
+                        Err(_) => break ::atoms::iter(),
+                    }
+                }
+
+                // The next value may follow right away.
+                Phase::After if format == StreamFormat::Concatenated => {
+                    iter_state.phase = Phase::Between;
+                }
+
+                Phase::After => {
+                    let scanned = match format {
+                        StreamFormat::Ndjson =>
+                            scan(&input, &mut iter_state.ss_state.position, is_line_whitespace),
+                        _ =>
+                            scan(&input, &mut iter_state.ss_state.position, is_whitespace),
+                    };
+                    iter_state.ss_state.first_needed = iter_state.ss_state.position;
+                    match scanned {
+                        Scan::Byte(b'\n') => {
+                            iter_state.ss_state.position += 1;
+                            iter_state.line += 1;
+                            iter_state.phase = Phase::Between;
+                        }
+                        // Skipped with the whitespace before the next record.
+                        Scan::Byte(RS) if format == StreamFormat::JsonSeq => {
+                            iter_state.phase = Phase::Between;
+                        }
+                        Scan::Byte(_) => {
+                            yields.push(format_trailing(env, &input, &iter_state.ss_state));
+                            if !format.recovers() {
+                                break ::atoms::finished();
+                            }
+                            iter_state.phase = Phase::Recover;
+                        }
+                        Scan::AwaitInput => break ::atoms::await_input(),
+                        Scan::Eof => {
+                            yields.push(::atoms::finished().encode(env));
+                            break ::atoms::finished();
+                        }
+                    }
+                }
+
+                Phase::Recover => {
+                    let scanned = scan(&input, &mut iter_state.ss_state.position,
+                                       |byte| !format.ends_record(byte));
+                    iter_state.ss_state.first_needed = iter_state.ss_state.position;
+                    match scanned {
+                        Scan::Byte(b'\n') => {
+                            iter_state.ss_state.position += 1;
+                            iter_state.line += 1;
+                            iter_state.phase = Phase::Between;
+                        }
+                        Scan::Byte(_) => iter_state.phase = Phase::Between,
+                        Scan::AwaitInput => break ::atoms::await_input(),
+                        Scan::Eof => {
+                            yields.push(::atoms::finished().encode(env));
+                            break ::atoms::finished();
+                        }
+                    }
+                }
+            }
+        };
+
+        let first_needed = iter_state.ss_state.first_needed;
+        // A tag scan doesn't need the input it went through again, but the
+        // parser will once the tag is found, so it is held on to instead of
+        // the binaries it came from.
+        let keep_from = match iter_state.ss_state.tag_scan {
+            Some(ref tag_scan) => max(tag_scan.first_needed(), first_needed),
+            None => first_needed,
+        };
+
+        let mut held = input.held;
+        held.release(first_needed, &mut iter_state.ss_state.lines);
+        held.hold(&binaries_ranges, first_needed, keep_from);
+
+        // The binaries are ordered newest first. Held input is counted once
+        // it is released.
+        let counted_to = held.start().unwrap_or(keep_from);
+        for &(ref range, bin) in binaries_ranges.iter().rev() {
+            if range.end < keep_from && range.start < counted_to {
+                let end = min(range.end, counted_to);
+                let lines = &mut iter_state.ss_state.lines;
+                lines.count(range.start, &bin.as_slice()[..(end - range.start)]);
+            }
         }
-        Err(_) => panic!("TODO: Add proper error"),
-    }
+        iter_state.held = held;
+
+        (status, out_stack, yields, keep_from)
+    };
+
+    let binaries_out = write_binaries(env, &binaries_ranges, keep_from);
+    let state = (out_stack, resource).encode(env);
+    Ok((status, yields, binaries_out, state).encode(env))
 }
@@ -6,10 +6,27 @@ use ::rustler::types::binary::OwnedNifBinary;
 
 use ::input_provider::InputProvider;
 
+/// Makes a binary term with a copy of the given bytes.
+pub fn bytes_to_term<'a>(env: NifEnv<'a>, bytes: &[u8]) -> NifTerm<'a> {
+    let mut bin = OwnedNifBinary::new(bytes.len()).unwrap();
+    bin.as_mut_slice().write(bytes).unwrap();
+    bin.release(env).encode(env)
+}
+
 pub enum BuildString {
     None,
     Range(Range),
-    Owned(Vec<u8>),
+    /// The bytes of the string, along with where the parts of it copied
+    /// from the input came from, as pairs of an offset into the bytes and
+    /// the position in the input.
+    Owned(Vec<u8>, Vec<(usize, usize)>),
+}
+
+/// Appends a range of the input to an owned string.
+fn push_source<F>(buf: &mut Vec<u8>, sources: &mut Vec<(usize, usize)>, range: Range,
+                  range_provider: &F) where F: Fn(Range, &mut Vec<u8>) {
+    sources.push((buf.len(), range.start));
+    range_provider(range, buf);
 }
 
 impl BuildString {
@@ -19,7 +36,7 @@ impl BuildString {
     }
 
     pub fn new_owned() -> BuildString {
-        BuildString::Owned(Vec::new())
+        BuildString::Owned(Vec::new(), Vec::new())
     }
 
     pub fn append_range<'a, F>(&'a mut self, range: Range, range_provider: F)
@@ -31,12 +48,13 @@ impl BuildString {
             },
             BuildString::Range(prev_range) => {
                 let mut buf: Vec<u8> = Vec::new();
-                range_provider(prev_range, &mut buf);
-                range_provider(range, &mut buf);
-                *self = BuildString::Owned(buf);
+                let mut sources = Vec::new();
+                push_source(&mut buf, &mut sources, prev_range, &range_provider);
+                push_source(&mut buf, &mut sources, range, &range_provider);
+                *self = BuildString::Owned(buf, sources);
             },
-            BuildString::Owned(ref mut buf) => {
-                range_provider(range, buf);
+            BuildString::Owned(ref mut buf, ref mut sources) => {
+                push_source(buf, sources, range, &range_provider);
             },
         }
     }
@@ -46,15 +64,16 @@ impl BuildString {
 
         match *self {
             BuildString::None => {
-                *self = BuildString::Owned(vec![single]);
+                *self = BuildString::Owned(vec![single], Vec::new());
             },
             BuildString::Range(prev_range) => {
                 let mut buf: Vec<u8> = Vec::new();
-                range_provider(prev_range, &mut buf);
+                let mut sources = Vec::new();
+                push_source(&mut buf, &mut sources, prev_range, &range_provider);
                 buf.push(single);
-                *self = BuildString::Owned(buf);
+                *self = BuildString::Owned(buf, sources);
             },
-            BuildString::Owned(ref mut buf) => {
+            BuildString::Owned(ref mut buf, _) => {
                 buf.push(single);
             },
         }
@@ -70,32 +89,71 @@ impl BuildString {
             BuildString::None => {
                 let mut vec = Vec::<u8>::new();
                 vec.extend_from_slice(codepoint_slice.as_bytes());
-                *self = BuildString::Owned(vec);
+                *self = BuildString::Owned(vec, Vec::new());
             },
             BuildString::Range(prev_range) => {
                 let mut buf: Vec<u8> = Vec::new();
-                range_provider(prev_range, &mut buf);
+                let mut sources = Vec::new();
+                push_source(&mut buf, &mut sources, prev_range, &range_provider);
                 buf.extend_from_slice(codepoint_slice.as_bytes());
-                *self = BuildString::Owned(buf);
+                *self = BuildString::Owned(buf, sources);
             },
-            BuildString::Owned(ref mut buf) => {
+            BuildString::Owned(ref mut buf, _) => {
                 buf.extend_from_slice(codepoint_slice.as_bytes());
             },
         }
     }
 
-    pub fn owned_to_vec(self) -> Vec<u8> {
+    /// Like `to_vec`, but takes the buffer instead of copying it if the
+    /// string is owned.
+    pub fn into_vec<T, M>(self, input: &T) -> Vec<u8> where T: InputProvider<M> {
         match self {
-            BuildString::Owned(vec) => vec,
-            _ => panic!(),
+            BuildString::Owned(vec, _) => vec,
+            other => other.to_vec(input),
+        }
+    }
+
+    pub fn to_vec<T, M>(&self, input: &T) -> Vec<u8> where T: InputProvider<M> {
+        match *self {
+            BuildString::None => Vec::new(),
+            BuildString::Range(range) => {
+                let mut buf = Vec::new();
+                input.push_range(range, &mut buf);
+                buf
+            }
+            BuildString::Owned(ref buf, _) => buf.clone(),
+        }
+    }
+
+    /// Fails if the string is not valid UTF-8, with the position of the
+    /// invalid byte in the input if it is known.
+    ///
+    /// Bytes added by escapes are always valid, so invalid bytes in an owned
+    /// string were copied from the input, and their position is known.
+    pub fn check_utf8<T, M>(&self, input: &T) -> Result<(), Option<usize>> where T: InputProvider<M> {
+        match *self {
+            BuildString::None => Ok(()),
+            BuildString::Range(range) => match input.invalid_utf8(range) {
+                Some(position) => Err(Some(position)),
+                None => Ok(()),
+            },
+            BuildString::Owned(ref buf, ref sources) => match ::std::str::from_utf8(buf) {
+                Ok(_) => Ok(()),
+                Err(error) => {
+                    let offset = error.valid_up_to();
+                    Err(sources.iter().rev()
+                        .find(|&&(start, _)| start <= offset)
+                        .map(|&(start, position)| position + offset - start))
+                }
+            },
         }
     }
 
-    pub fn to_term<'a, T, M>(self, input: &mut T, env: NifEnv<'a>) -> NifTerm<'a> where T: InputProvider<M> {
+    pub fn to_term<'a, T, M>(self, input: &T, env: NifEnv<'a>) -> NifTerm<'a> where T: InputProvider<M> {
         match self {
             BuildString::None => "".encode(env),
             BuildString::Range(range) => input.range_to_term(env, range),
-            BuildString::Owned(ref buf) => {
+            BuildString::Owned(ref buf, _) => {
                 let mut bin = OwnedNifBinary::new(buf.len()).unwrap();
                 bin.as_mut_slice().write(buf).unwrap();
                 bin.release(env).encode(env)
@@ -5,13 +5,20 @@ use ::rustler::types::map::NifMapIterator;
 use ::rustler::types::atom::NifAtom;
 
 use super::{
+    ValueType,
     NodeOptions,
     NodeId,
     Node,
     NodeVariant,
+    UnknownKeys,
+    MatcherDefault,
+    LengthUnit,
+    NumberBound,
     Spec,
 };
 
+use ::numbers::term_to_bigint;
+
 mod atoms {
     rustler_atoms! {
         atom stream;
@@ -19,15 +26,58 @@ mod atoms {
         atom map;
         atom map_keys;
         atom array;
+        atom tuple;
+        atom as_tuple;
         atom struct_atom;
         atom atom_keys;
         atom ignore_non_atoms;
+        atom reject_non_atoms;
+        atom unknown_keys;
+        atom error;
+        atom ignore;
+        atom node;
+        atom enum_ = "enum";
+        atom values;
+        atom static_ = "static";
+        atom value;
+        atom skip;
+        atom tagged_map;
+        atom key_name;
+        atom mappings;
+        atom default;
+        atom tag_array;
+        atom type_ = "type";
+        atom type_mappings;
+        atom string;
+        atom number;
+        atom bool;
+        atom null;
+        atom strict;
+        atom required;
+        atom defaults;
+        atom min_length;
+        atom max_length;
+        atom length_unit;
+        atom bytes;
+        atom codepoints;
+        atom min;
+        atom max;
+        atom integer_only;
+        atom non_negative;
     }
 }
 
-fn read_opts<'a>(term: NifTerm<'a>, stream_collect: bool) -> NifResult<NodeOptions> {
+fn read_number_bound<'a>(term: NifTerm<'a>) -> NifResult<NumberBound> {
+    if let Ok(float) = term.decode::<f64>() {
+        return Ok(NumberBound::Float(float));
+    }
+    term_to_bigint(term).map(NumberBound::Integer).ok_or(NifError::BadArg)
+}
+
+fn read_opts<'a>(term: NifTerm<'a>, stream_collect: bool, strict: bool) -> NifResult<NodeOptions> {
     let iterator: NifListIterator = term.decode()?;
     let mut opts = NodeOptions::default();
+    opts.strict = strict;
     for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
         let (key, value) = decoded?;
 
@@ -46,20 +96,261 @@ fn read_opts<'a>(term: NifTerm<'a>, stream_collect: bool) -> NifResult<NodeOptio
             opts.atom_mappings = Some(map);
         } else if atoms::ignore_non_atoms() == key {
             opts.ignore_non_atoms = value.decode()?;
+        } else if atoms::reject_non_atoms() == key {
+            opts.reject_non_atoms = value.decode()?;
+        } else if atoms::as_tuple() == key {
+            opts.as_tuple = value.decode()?;
+        } else if atoms::strict() == key {
+            opts.strict = value.decode()?;
+        } else if atoms::required() == key {
+            let mut required: Vec<Vec<u8>> = Vec::new();
+            for key_term in value.decode::<NifListIterator>()? {
+                let key: String = key_term.decode()?;
+                required.push(key.into_bytes());
+            }
+            required.sort();
+            opts.required = Some(required);
+        } else if atoms::defaults() == key {
+            let mut defaults: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+            for (key_term, default_term) in value.decode::<NifMapIterator>()? {
+                let key: String = key_term.decode()?;
+                // Stored serialized, like static values.
+                defaults.push((key.into_bytes(), default_term.to_binary().as_slice().to_vec()));
+            }
+            defaults.sort();
+            opts.defaults = defaults;
+        } else if atoms::min_length() == key {
+            opts.min_length = Some(value.decode()?);
+        } else if atoms::max_length() == key {
+            opts.max_length = Some(value.decode()?);
+        } else if atoms::length_unit() == key {
+            opts.length_unit = if atoms::bytes() == value {
+                LengthUnit::Bytes
+            } else if atoms::codepoints() == value {
+                LengthUnit::Codepoints
+            } else {
+                return Err(NifError::BadArg);
+            };
+        } else if atoms::min() == key {
+            opts.min = Some(read_number_bound(value)?);
+        } else if atoms::max() == key {
+            opts.max = Some(read_number_bound(value)?);
+        } else if atoms::integer_only() == key {
+            opts.integer_only = value.decode()?;
+        } else if atoms::non_negative() == key {
+            opts.non_negative = value.decode()?;
         }
 
     }
+    if opts.ignore_non_atoms && opts.reject_non_atoms {
+        return Err(NifError::BadArg);
+    }
     opts.stream_collect = opts.stream | stream_collect;
     Ok(opts)
 }
 
+fn read_unknown_keys<'a>(opts: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool) -> NifResult<UnknownKeys> {
+    let iterator: NifListIterator = opts.decode()?;
+    let mut unknown = UnknownKeys::Pass;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::unknown_keys() == key {
+            unknown = if atoms::error() == value {
+                UnknownKeys::Error
+            } else if atoms::ignore() == value {
+                UnknownKeys::Ignore
+            } else {
+                let (typ, node): (NifTerm, NifTerm) = value.decode()?;
+                if atoms::node() != typ {
+                    return Err(NifError::BadArg);
+                }
+                UnknownKeys::Node(read_node(node, nodes, parent, stream_collect)?)
+            };
+        }
+    }
+    Ok(unknown)
+}
+
+fn read_enum_values<'a>(opts: NifTerm<'a>) -> NifResult<HashMap<Vec<u8>, NifAtom>> {
+    let iterator: NifListIterator = opts.decode()?;
+    let mut values: Option<HashMap<Vec<u8>, NifAtom>> = None;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::values() == key {
+            let mut map: HashMap<Vec<u8>, NifAtom> = HashMap::new();
+            if let Ok(iterator) = value.decode::<NifMapIterator>() {
+                for (string_term, atom_term) in iterator {
+                    let string: String = string_term.decode()?;
+                    map.insert(string.into_bytes(), atom_term.decode()?);
+                }
+            } else {
+                let iterator: NifListIterator = value.decode()?;
+                for string_term in iterator {
+                    let string: String = string_term.decode()?;
+                    let atom = NifAtom::from_str(string_term.get_env(), &string)?;
+                    map.insert(string.into_bytes(), atom);
+                }
+            }
+            values = Some(map);
+        }
+    }
+    values.ok_or(NifError::BadArg)
+}
+
+fn read_static_value<'a>(opts: NifTerm<'a>) -> NifResult<Vec<u8>> {
+    let iterator: NifListIterator = opts.decode()?;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::value() == key {
+            // The spec outlives the env the term is in, store it serialized.
+            return Ok(value.to_binary().as_slice().to_vec());
+        }
+    }
+    Err(NifError::BadArg)
+}
+
+fn read_mappings<'a>(term: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool, strict: bool) -> NifResult<HashMap<Vec<u8>, NodeId>> {
+    let mut mappings = HashMap::<Vec<u8>, NodeId>::new();
+    for (key, value) in term.decode::<NifMapIterator>()? {
+        let key: String = key.decode()?;
+        let child = read_node_inheriting(value, nodes, parent, stream_collect, strict)?;
+        mappings.insert(key.into_bytes(), child);
+    }
+    Ok(mappings)
+}
+
+fn read_matcher_default<'a>(term: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool, strict: bool) -> NifResult<MatcherDefault> {
+    if atoms::error() == term {
+        Ok(MatcherDefault::Error)
+    } else if atoms::ignore() == term {
+        // Values without a mapping are parsed as plain JSON.
+        let current = NodeId(nodes.len());
+        nodes.push(Node {
+            variant: NodeVariant::Any,
+            options: NodeOptions {
+                stream_collect: stream_collect,
+                strict: strict,
+                ..NodeOptions::default()
+            },
+            parent: Some(parent),
+        });
+        Ok(MatcherDefault::Node(current))
+    } else {
+        let (typ, node): (NifTerm, NifTerm) = term.decode()?;
+        if atoms::node() != typ {
+            return Err(NifError::BadArg);
+        }
+        Ok(MatcherDefault::Node(read_node_inheriting(node, nodes, parent, stream_collect, strict)?))
+    }
+}
+
+/// Reads the options of a tagged map. The mapped nodes are read as children
+/// of `parent`, which should be the parent of the tagged map itself, but
+/// inherit `strict` from the tagged map.
+fn read_tagged_map<'a>(opts: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool, strict: bool) -> NifResult<NodeVariant> {
+    let iterator: NifListIterator = opts.decode()?;
+    let mut key_name: Option<Vec<u8>> = None;
+    let mut mappings = HashMap::<Vec<u8>, NodeId>::new();
+    let mut default = MatcherDefault::Error;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::key_name() == key {
+            let name: String = value.decode()?;
+            key_name = Some(name.into_bytes());
+        } else if atoms::mappings() == key {
+            mappings = read_mappings(value, nodes, parent, stream_collect, strict)?;
+        } else if atoms::default() == key {
+            default = read_matcher_default(value, nodes, parent, stream_collect, strict)?;
+        }
+    }
+    Ok(NodeVariant::TaggedMap {
+        key: key_name.ok_or(NifError::BadArg)?,
+        mappings: mappings,
+        default: default,
+    })
+}
+
+/// Reads the options of a tag array. The mapped nodes are read as children
+/// of `parent`, which should be the tag array itself.
+fn read_tag_array<'a>(opts: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool) -> NifResult<NodeVariant> {
+    let strict = nodes[parent.0].options.strict;
+    let iterator: NifListIterator = opts.decode()?;
+    let mut mappings = HashMap::<Vec<u8>, NodeId>::new();
+    let mut default = MatcherDefault::Error;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::mappings() == key {
+            mappings = read_mappings(value, nodes, parent, stream_collect, strict)?;
+        } else if atoms::default() == key {
+            default = read_matcher_default(value, nodes, parent, stream_collect, strict)?;
+        }
+    }
+    Ok(NodeVariant::TagArray {
+        mappings: mappings,
+        default: default,
+    })
+}
+
+fn read_value_type<'a>(term: NifTerm<'a>) -> NifResult<ValueType> {
+    if atoms::map() == term {
+        Ok(ValueType::Object)
+    } else if atoms::array() == term {
+        Ok(ValueType::Array)
+    } else if atoms::string() == term {
+        Ok(ValueType::String)
+    } else if atoms::number() == term {
+        Ok(ValueType::Number)
+    } else if atoms::bool() == term {
+        Ok(ValueType::Boolean)
+    } else if atoms::null() == term {
+        Ok(ValueType::Null)
+    } else {
+        Err(NifError::BadArg)
+    }
+}
+
+/// Reads the options of a type matcher. Like for tagged maps, the mapped
+/// nodes are read as children of the parent of the matcher, and inherit
+/// `strict` from the matcher.
+fn read_type_mappings<'a>(opts: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool, strict: bool) -> NifResult<NodeVariant> {
+    let iterator: NifListIterator = opts.decode()?;
+    let mut mappings: Option<HashMap<ValueType, NodeId>> = None;
+    for decoded in iterator.map(|term| term.decode::<(NifTerm, NifTerm)>()) {
+        let (key, value) = decoded?;
+
+        if atoms::type_mappings() == key {
+            let mut map = HashMap::<ValueType, NodeId>::new();
+            for (typ, node) in value.decode::<NifMapIterator>()? {
+                let typ = read_value_type(typ)?;
+                map.insert(typ, read_node_inheriting(node, nodes, parent, stream_collect, strict)?);
+            }
+            mappings = Some(map);
+        }
+    }
+    Ok(NodeVariant::Type {
+        mappings: mappings.ok_or(NifError::BadArg)?,
+    })
+}
+
 fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool) -> NifResult<NodeId> {
+    let strict = nodes[parent.0].options.strict;
+    read_node_inheriting(node, nodes, parent, stream_collect, strict)
+}
+
+/// Reads a node that inherits `strict` from somewhere else than its parent,
+/// as the nodes matchers map to do.
+fn read_node_inheriting<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, stream_collect: bool, strict: bool) -> NifResult<NodeId> {
     let current = NodeId(nodes.len());
 
     // Arity 3
     match node.decode::<(NifTerm, NifTerm, NifTerm)>() {
-        Ok((typ, opts, data)) => {
-            let opts = read_opts(opts, stream_collect)?;
+        Ok((typ, opts_term, data)) => {
+            let opts = read_opts(opts_term, stream_collect, strict)?;
             let child_stream_collect = opts.stream_collect;
 
             return if atoms::map() == typ {
@@ -82,13 +373,16 @@ fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, strea
                     parent: Some(parent),
                 });
 
-                let mut children = HashMap::<String, NodeId>::new();
+                let mut children = HashMap::<Vec<u8>, NodeId>::new();
                 for (key, value) in data.decode::<NifMapIterator>()? {
+                    let key: String = key.decode()?;
                     let child = read_node(value, nodes, current, child_stream_collect)?;
-                    children.insert(key.decode()?, child);
+                    children.insert(key.into_bytes(), child);
                 }
+                let unknown = read_unknown_keys(opts_term, nodes, current, child_stream_collect)?;
                 nodes[current.0].variant = NodeVariant::MapKeys {
                     children: children,
+                    unknown: unknown,
                 };
 
                 Ok(current)
@@ -104,6 +398,22 @@ fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, strea
                     child: child,
                 };
 
+                Ok(current)
+            } else if atoms::tuple() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Sentinel,
+                    options: opts,
+                    parent: Some(parent),
+                });
+
+                let mut children = Vec::<NodeId>::new();
+                for child_term in data.decode::<NifListIterator>()? {
+                    children.push(read_node(child_term, nodes, current, child_stream_collect)?);
+                }
+                nodes[current.0].variant = NodeVariant::Tuple {
+                    children: children,
+                };
+
                 Ok(current)
             } else {
                 Err(NifError::BadArg)
@@ -115,8 +425,8 @@ fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, strea
 
     // Arity 2
     match node.decode::<(NifTerm, NifTerm)>() {
-        Ok((typ, opts)) => {
-            let opts = read_opts(opts, stream_collect)?;
+        Ok((typ, opts_term)) => {
+            let opts = read_opts(opts_term, stream_collect, strict)?;
 
             return if atoms::any() == typ {
                 nodes.push(Node {
@@ -125,6 +435,67 @@ fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, strea
                     parent: Some(parent),
                 });
                 Ok(current)
+            } else if atoms::enum_() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Enum {
+                        values: read_enum_values(opts_term)?,
+                    },
+                    options: opts,
+                    parent: Some(parent),
+                });
+                Ok(current)
+            } else if atoms::tagged_map() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Sentinel,
+                    options: opts,
+                    parent: Some(parent),
+                });
+
+                let strict = nodes[current.0].options.strict;
+                let variant = read_tagged_map(opts_term, nodes, parent, stream_collect, strict)?;
+                nodes[current.0].variant = variant;
+
+                Ok(current)
+            } else if atoms::tag_array() == typ {
+                let child_stream_collect = opts.stream_collect;
+                nodes.push(Node {
+                    variant: NodeVariant::Sentinel,
+                    options: opts,
+                    parent: Some(parent),
+                });
+
+                let variant = read_tag_array(opts_term, nodes, current, child_stream_collect)?;
+                nodes[current.0].variant = variant;
+
+                Ok(current)
+            } else if atoms::type_() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Sentinel,
+                    options: opts,
+                    parent: Some(parent),
+                });
+
+                let strict = nodes[current.0].options.strict;
+                let variant = read_type_mappings(opts_term, nodes, parent, stream_collect, strict)?;
+                nodes[current.0].variant = variant;
+
+                Ok(current)
+            } else if atoms::static_() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Static {
+                        value: read_static_value(opts_term)?,
+                    },
+                    options: opts,
+                    parent: Some(parent),
+                });
+                Ok(current)
+            } else if atoms::skip() == typ {
+                nodes.push(Node {
+                    variant: NodeVariant::Skip,
+                    options: opts,
+                    parent: Some(parent),
+                });
+                Ok(current)
             } else {
                 Err(NifError::BadArg)
             };
@@ -136,6 +507,25 @@ fn read_node<'a>(node: NifTerm<'a>, nodes: &mut Vec<Node>, parent: NodeId, strea
     Err(NifError::BadArg)
 }
 
+/// Skipped values are dropped from the map or array they are in, so skip
+/// nodes can only be used directly as the value of a key or index. Matchers
+/// have to produce a value, and so do tuple elements, which would otherwise
+/// shift the ones after them.
+fn validate_skip_nodes(nodes: &[Node]) -> NifResult<()> {
+    for (idx, node) in nodes.iter().enumerate() {
+        if node.variant.drops_value() {
+            let parent = &nodes[node.parent.unwrap().0];
+            if !parent.variant.has_direct_child(NodeId(idx)) {
+                return Err(NifError::BadArg);
+            }
+            if let NodeVariant::Tuple { .. } = parent.variant {
+                return Err(NifError::BadArg);
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn spec_from_term<'a>(root: NifTerm<'a>) -> NifResult<Spec> {
     let mut nodes = Vec::<Node>::new();
 
@@ -148,6 +538,7 @@ pub fn spec_from_term<'a>(root: NifTerm<'a>) -> NifResult<Spec> {
     let sentinel_id = NodeId(0);
 
     assert_eq!(read_node(root, &mut nodes, sentinel_id, false)?, NodeId(1));
+    validate_skip_nodes(&nodes)?;
 
     Ok(Spec {
         nodes: nodes,
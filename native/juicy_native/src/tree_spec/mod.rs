@@ -1,13 +1,33 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use rustler::{NifEnv, NifTerm, NifResult, NifEncoder};
+use rustler::resource::ResourceArc;
 use rustler::types::atom::NifAtom;
 
+use num_bigint::BigInt;
+
+use ::numbers::{bigint_to_term, bigint_to_f64};
+
 mod from_term;
+mod selector;
 mod walker;
 
 pub use self::from_term::spec_from_term;
-pub use self::walker::{SpecWalker, PathEntry, PathPosition};
+pub use self::selector::spec_from_selector;
+pub use self::walker::{SpecWalker, PathEntry, PathPosition, KeyPosition, KeyAction};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+mod atoms {
+    rustler_atoms! {
+        atom map;
+        atom array;
+        atom string;
+        atom number;
+        atom bool;
+        atom null;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ValueType {
     Object,
     Array,
@@ -17,10 +37,40 @@ pub enum ValueType {
     Null,
 }
 
+impl NifEncoder for ValueType {
+    fn encode<'a>(&self, env: NifEnv<'a>) -> NifTerm<'a> {
+        match *self {
+            ValueType::Object => atoms::map().encode(env),
+            ValueType::Array => atoms::array().encode(env),
+            ValueType::String => atoms::string().encode(env),
+            ValueType::Number => atoms::number().encode(env),
+            ValueType::Boolean => atoms::bool().encode(env),
+            ValueType::Null => atoms::null().encode(env),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct NodeId(usize);
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Looks up what node a tagged map or tag array should use, given the tag.
+///
+/// Returns `Ok(None)` when the tag has no mapping and the default is to
+/// fail, and `Err(())` when the node is neither.
+pub fn resolve_tag(variant: &NodeVariant, tag: Option<&[u8]>) -> Result<Option<NodeId>, ()> {
+    let (mappings, default) = match variant {
+        &NodeVariant::TaggedMap { ref mappings, default, .. } => (mappings, default),
+        &NodeVariant::TagArray { ref mappings, default } => (mappings, default),
+        _ => return Err(()),
+    };
+    Ok(match (tag.and_then(|tag| mappings.get(tag)), default) {
+        (Some(&node_id), _) => Some(node_id),
+        (None, MatcherDefault::Node(node_id)) => Some(node_id),
+        (None, MatcherDefault::Error) => None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub variant: NodeVariant,
     pub options: NodeOptions,
@@ -32,8 +82,64 @@ pub enum NodeVariant {
     Sentinel,
     Any,
     Array { child: NodeId, },
+    Tuple { children: Vec<NodeId>, },
+    Enum { values: HashMap<Vec<u8>, NifAtom>, },
+    /// The value is always replaced with a static term, stored in the
+    /// external term format.
+    Static { value: Vec<u8>, },
+    /// The value is validated by the parser, but is left out of the result
+    /// without ever being built.
+    Skip,
     Map { child: NodeId, },
-    MapKeys { children: HashMap<String, NodeId>, },
+    MapKeys { children: HashMap<Vec<u8>, NodeId>, unknown: UnknownKeys, },
+    /// Matcher for maps where the value of a single key decides what node
+    /// the map is parsed as. The mapped nodes share the parent of this node,
+    /// so that the walker can move to them once the tag is known.
+    TaggedMap { key: Vec<u8>, mappings: HashMap<Vec<u8>, NodeId>, default: MatcherDefault, },
+    /// Matcher for arrays of the form `[tag, value]`, where the tag decides
+    /// what node the value is parsed as.
+    TagArray { mappings: HashMap<Vec<u8>, NodeId>, default: MatcherDefault, },
+    /// Matcher that decides what node to use from the type of the value.
+    /// Like `TaggedMap`, the mapped nodes share the parent of this node.
+    Type { mappings: HashMap<ValueType, NodeId>, },
+    /// Map or array compiled from a selector, with a node for every key
+    /// and index the selector names, and one for all others. Only values
+    /// streamed from inside of it are output, it is left empty itself.
+    Select {
+        keys: HashMap<Vec<u8>, NodeId>,
+        indices: HashMap<usize, NodeId>,
+        other_keys: NodeId,
+        other_indices: NodeId,
+    },
+}
+
+/// What a matcher does with values it has no mapping for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatcherDefault {
+    /// The parse fails.
+    Error,
+    /// The value is parsed with the given node.
+    Node(NodeId),
+}
+
+/// What string lengths are counted in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LengthUnit {
+    Bytes,
+    Codepoints,
+}
+
+/// What a `MapKeys` node does with keys that are not in its `children`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnknownKeys {
+    /// The value is parsed as plain JSON, outside of the spec.
+    Pass,
+    /// The key and value are left out of the resulting map.
+    Ignore,
+    /// The parse fails.
+    Error,
+    /// The value is parsed with the given node.
+    Node(NodeId),
 }
 
 impl NodeVariant {
@@ -42,9 +148,18 @@ impl NodeVariant {
         match self {
             &NodeVariant::Sentinel => unreachable!(),
             &NodeVariant::Any => true,
+            &NodeVariant::Static { .. } => true,
+            &NodeVariant::Skip => true,
             &NodeVariant::Map { .. } if value == ValueType::Object => true,
             &NodeVariant::MapKeys { .. } if value == ValueType::Object => true,
+            &NodeVariant::TaggedMap { .. } if value == ValueType::Object => true,
             &NodeVariant::Array { .. } if value == ValueType::Array => true,
+            &NodeVariant::Tuple { .. } if value == ValueType::Array => true,
+            &NodeVariant::TagArray { .. } if value == ValueType::Array => true,
+            &NodeVariant::Enum { .. } if value == ValueType::String => true,
+            &NodeVariant::Type { ref mappings } => mappings.contains_key(&value),
+            &NodeVariant::Select { .. } =>
+                value == ValueType::Object || value == ValueType::Array,
             _ => false,
         }
     }
@@ -57,35 +172,204 @@ impl NodeVariant {
         }
     }
 
-    pub fn child_key(&self, _key: &[u8]) -> Option<NodeId> {
+    pub fn child_key(&self, key: &[u8]) -> Option<NodeId> {
         match self {
             &NodeVariant::Sentinel => unreachable!(),
             &NodeVariant::Any => None,
             &NodeVariant::Map { child } => Some(child),
+            &NodeVariant::MapKeys { ref children, unknown } => {
+                match (children.get(key), unknown) {
+                    (Some(&child), _) => Some(child),
+                    (None, UnknownKeys::Node(child)) => Some(child),
+                    (None, _) => None,
+                }
+            }
             &NodeVariant::Array { .. } => None,
-            _ => unimplemented!(),
+            &NodeVariant::Tuple { .. } => None,
+            &NodeVariant::Enum { .. } => None,
+            &NodeVariant::Static { .. } => None,
+            &NodeVariant::Skip => None,
+            &NodeVariant::TaggedMap { .. } => None,
+            &NodeVariant::TagArray { .. } => None,
+            // Only current when it has no mapping for a matcher's value.
+            &NodeVariant::Type { .. } => None,
+            &NodeVariant::Select { ref keys, other_keys, .. } =>
+                Some(keys.get(key).cloned().unwrap_or(other_keys)),
         }
     }
 
-    pub fn child_index(&self, _index: usize) -> Option<NodeId> {
+    pub fn child_index(&self, index: usize) -> Option<NodeId> {
         match self {
             &NodeVariant::Sentinel => unreachable!(),
             &NodeVariant::Any => None,
             &NodeVariant::Map { .. } => None,
+            &NodeVariant::MapKeys { .. } => None,
             &NodeVariant::Array { child } => Some(child),
-            _ => unimplemented!(),
+            &NodeVariant::Tuple { ref children } => children.get(index).cloned(),
+            &NodeVariant::Enum { .. } => None,
+            &NodeVariant::Static { .. } => None,
+            &NodeVariant::Skip => None,
+            &NodeVariant::TaggedMap { .. } => None,
+            // The walker substitutes the node selected by the tag, this is
+            // only used when no tag was seen.
+            &NodeVariant::TagArray { .. } if index == 1 => resolve_tag(self, None).unwrap_or(None),
+            &NodeVariant::TagArray { .. } => None,
+            &NodeVariant::Type { .. } => None,
+            &NodeVariant::Select { ref indices, other_indices, .. } =>
+                Some(indices.get(&index).cloned().unwrap_or(other_indices)),
+        }
+    }
+
+    /// Whether values that don't match this node fail the parse, even
+    /// outside of strict mode.
+    pub fn rejects_mismatch(&self) -> bool {
+        match self {
+            &NodeVariant::Enum { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether no output should be built for values matching this node.
+    pub fn skips_output(&self) -> bool {
+        match self {
+            &NodeVariant::Static { .. } => true,
+            &NodeVariant::Skip => true,
+            _ => false,
+        }
+    }
+
+    /// Whether values matching this node are left out of the map or array
+    /// they are in.
+    pub fn drops_value(&self) -> bool {
+        self == &NodeVariant::Skip
+    }
+
+    /// Whether values put into maps and arrays matching this node are
+    /// thrown away instead.
+    pub fn discards_children(&self) -> bool {
+        match self {
+            &NodeVariant::Select { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this node refers to `child` as the node for one of its keys
+    /// or indices, as opposed to through a matcher.
+    pub fn has_direct_child(&self, child: NodeId) -> bool {
+        match self {
+            &NodeVariant::Map { child: map_child } => map_child == child,
+            &NodeVariant::MapKeys { ref children, unknown } =>
+                children.values().any(|&id| id == child) || unknown == UnknownKeys::Node(child),
+            &NodeVariant::Array { child: array_child } => array_child == child,
+            &NodeVariant::Tuple { ref children } => children.contains(&child),
+            &NodeVariant::Select { ref keys, ref indices, other_keys, other_indices } =>
+                keys.values().chain(indices.values()).any(|&id| id == child) ||
+                other_keys == child || other_indices == child,
+            _ => false,
+        }
+    }
+
+    pub fn key_action(&self, key: &[u8], strict: bool) -> KeyAction {
+        match self {
+            &NodeVariant::MapKeys { ref children, unknown } if !children.contains_key(key) => {
+                match unknown {
+                    UnknownKeys::Ignore => KeyAction::Ignore,
+                    UnknownKeys::Error => KeyAction::Error,
+                    UnknownKeys::Pass if strict => KeyAction::Mismatch,
+                    _ => KeyAction::Keep,
+                }
+            }
+            _ => KeyAction::Keep,
+        }
+    }
+
+    /// The types of values this node matches, used when reporting a
+    /// mismatch.
+    pub fn expected_types(&self) -> Vec<ValueType> {
+        let all = vec![ValueType::Object, ValueType::Array, ValueType::String,
+                       ValueType::Number, ValueType::Boolean, ValueType::Null];
+        match self {
+            &NodeVariant::Map { .. } | &NodeVariant::MapKeys { .. } |
+            &NodeVariant::TaggedMap { .. } => vec![ValueType::Object],
+            &NodeVariant::Array { .. } | &NodeVariant::Tuple { .. } |
+            &NodeVariant::TagArray { .. } => vec![ValueType::Array],
+            &NodeVariant::Enum { .. } => vec![ValueType::String],
+            &NodeVariant::Select { .. } => vec![ValueType::Object, ValueType::Array],
+            &NodeVariant::Type { ref mappings } => {
+                let mut types: Vec<ValueType> = mappings.keys().cloned().collect();
+                types.sort();
+                types
+            }
+            &NodeVariant::Any | &NodeVariant::Static { .. } | &NodeVariant::Skip => all,
+            &NodeVariant::Sentinel => Vec::new(),
+        }
+    }
+
+    /// Keys known by this node, used when reporting an unknown key.
+    pub fn known_keys(&self) -> Vec<&[u8]> {
+        match self {
+            &NodeVariant::MapKeys { ref children, .. } => {
+                let mut keys: Vec<&[u8]> = children.keys().map(|key| &key[..]).collect();
+                keys.sort();
+                keys
+            }
+            _ => Vec::new(),
         }
     }
 
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A numeric bound from a spec, kept in the form it was given in.
+/// Integers can be of any size.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NumberBound {
+    Integer(BigInt),
+    Float(f64),
+}
+
+impl NumberBound {
+    pub fn to_f64(&self) -> f64 {
+        match *self {
+            NumberBound::Integer(ref integer) => bigint_to_f64(integer),
+            NumberBound::Float(float) => float,
+        }
+    }
+}
+
+impl NifEncoder for NumberBound {
+    fn encode<'a>(&self, env: NifEnv<'a>) -> NifTerm<'a> {
+        match *self {
+            NumberBound::Integer(ref integer) => bigint_to_term(env, integer),
+            NumberBound::Float(float) => float.encode(env),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct NodeOptions {
     pub stream: bool,
     pub stream_collect: bool,
     pub struct_atom: Option<NifAtom>,
     pub atom_mappings: Option<HashMap<Vec<u8>, NifAtom>>,
+    /// Keys without an atom in `atom_mappings` are left out of the map.
     pub ignore_non_atoms: bool,
+    /// Keys without an atom in `atom_mappings` fail the parse.
+    pub reject_non_atoms: bool,
+    pub as_tuple: bool,
+    /// Values not matching the spec fail the parse. Inherited by children.
+    pub strict: bool,
+    /// Keys that must be present in the map, in sorted order.
+    pub required: Option<Vec<Vec<u8>>>,
+    /// Values for keys that are absent from the map, in the external term
+    /// format. Sorted by key.
+    pub defaults: Vec<(Vec<u8>, Vec<u8>)>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub length_unit: LengthUnit,
+    pub min: Option<NumberBound>,
+    pub max: Option<NumberBound>,
+    pub integer_only: bool,
+    pub non_negative: bool,
 }
 impl Default for NodeOptions {
     fn default() -> Self {
@@ -95,11 +379,70 @@ impl Default for NodeOptions {
             struct_atom: None,
             atom_mappings: None,
             ignore_non_atoms: false,
+            reject_non_atoms: false,
+            as_tuple: false,
+            strict: false,
+            required: None,
+            defaults: Vec::new(),
+            min_length: None,
+            max_length: None,
+            length_unit: LengthUnit::Codepoints,
+            min: None,
+            max: None,
+            integer_only: false,
+            non_negative: false,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl NodeOptions {
+
+    /// What should be done with a key that the node itself would keep,
+    /// depending on whether it has an atom.
+    pub fn non_atom_action(&self, key: &[u8]) -> KeyAction {
+        match self.atom_mappings {
+            Some(ref mappings) if !mappings.contains_key(key) => {
+                if self.reject_non_atoms {
+                    KeyAction::Error
+                } else if self.ignore_non_atoms {
+                    KeyAction::Ignore
+                } else {
+                    KeyAction::Keep
+                }
+            }
+            _ => KeyAction::Keep,
+        }
+    }
+    /// Whether we need to keep track of having seen the key in maps
+    /// matching this node, because it is required or has a default.
+    pub fn tracks_key(&self, key: &[u8]) -> bool {
+        let required = self.required.as_ref()
+            .map_or(false, |required| required.binary_search_by(|required| required[..].cmp(key)).is_ok());
+        required || self.has_default(key)
+    }
+
+    /// The unit string lengths should be counted in, if strings matching
+    /// this node have length constraints.
+    pub fn counted_length(&self) -> Option<LengthUnit> {
+        if self.min_length.is_some() || self.max_length.is_some() {
+            Some(self.length_unit)
+        } else {
+            None
+        }
+    }
+
+    /// Whether numbers matching this node have constraints.
+    pub fn constrains_numbers(&self) -> bool {
+        self.min.is_some() || self.max.is_some() || self.integer_only || self.non_negative
+    }
+
+    /// Whether the key is filled in with a default when it is absent.
+    pub fn has_default(&self, key: &[u8]) -> bool {
+        self.defaults.binary_search_by(|&(ref default_key, _)| default_key[..].cmp(key)).is_ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Spec {
     nodes: Vec<Node>,
     root: NodeId,
@@ -112,4 +455,38 @@ impl Spec {
     pub fn root_id(&self) -> NodeId {
         self.root
     }
+
+    /// The node for the top level value.
+    pub fn root_node(&self) -> &Node {
+        self.get(self.get(self.root).variant.child_root().unwrap())
+    }
+
+    /// Follows type matchers from `node` to the node for a value of type
+    /// `typ`, which may take more than one step since they can be nested.
+    ///
+    /// Returns `None` when a type matcher has no mapping for the type.
+    pub fn resolve_type(&self, node: NodeId, typ: ValueType) -> Option<NodeId> {
+        let mut node = node;
+        loop {
+            match self.get(node).variant {
+                NodeVariant::Type { ref mappings } => match mappings.get(&typ) {
+                    Some(&mapped) => node = mapped,
+                    None => return None,
+                },
+                _ => return Some(node),
+            }
+        }
+    }
+}
+
+/// A spec that has been read once, and can be used for any number of parses.
+pub struct CompiledSpec(pub Arc<Spec>);
+
+/// Reads the spec for a parse, either from a compiled spec or by reading
+/// the spec term.
+pub fn read_spec<'a>(term: NifTerm<'a>) -> NifResult<Arc<Spec>> {
+    match term.decode::<ResourceArc<CompiledSpec>>() {
+        Ok(compiled) => Ok(compiled.0.clone()),
+        Err(_) => Ok(Arc::new(spec_from_term(term)?)),
+    }
 }
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use super::{NodeOptions, NodeId, Node, NodeVariant, Spec};
+
+/// What a segment of a selector picks out of a map or array.
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    Key(Vec<u8>),
+    Index(usize),
+    Wildcard,
+}
+
+impl Selector {
+    fn matches_key(&self, key: &[u8]) -> bool {
+        match self {
+            &Selector::Key(ref name) => &name[..] == key,
+            &Selector::Index(_) => false,
+            &Selector::Wildcard => true,
+        }
+    }
+
+    fn matches_index(&self, index: usize) -> bool {
+        match self {
+            &Selector::Key(_) => false,
+            &Selector::Index(selected) => selected == index,
+            &Selector::Wildcard => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    selector: Selector,
+    /// Applies to values at any depth below, not only to direct children.
+    descendant: bool,
+}
+
+/// Reads a name after a `.`, which goes on until the next segment.
+fn parse_name(input: &[u8], position: &mut usize) -> Option<Selector> {
+    if input.get(*position) == Some(&b'*') {
+        *position += 1;
+        return Some(Selector::Wildcard);
+    }
+    let start = *position;
+    while *position < input.len() && input[*position] != b'.' && input[*position] != b'[' {
+        *position += 1;
+    }
+    if *position == start {
+        return None;
+    }
+    Some(Selector::Key(input[start..*position].to_vec()))
+}
+
+fn is_digit(byte: u8) -> bool {
+    byte >= b'0' && byte <= b'9'
+}
+
+/// Reads a `[...]` segment, either `*`, an index or a quoted name.
+fn parse_bracket(input: &[u8], position: &mut usize) -> Option<Selector> {
+    *position += 1;
+    let selector = match input.get(*position) {
+        Some(&b'*') => {
+            *position += 1;
+            Selector::Wildcard
+        }
+        Some(&quote) if quote == b'\'' || quote == b'"' => {
+            *position += 1;
+            let mut name = Vec::new();
+            loop {
+                match input.get(*position) {
+                    Some(&byte) if byte == quote => break,
+                    Some(&b'\\') => {
+                        name.push(*input.get(*position + 1)?);
+                        *position += 2;
+                    }
+                    Some(&byte) => {
+                        name.push(byte);
+                        *position += 1;
+                    }
+                    None => return None,
+                }
+            }
+            *position += 1;
+            Selector::Key(name)
+        }
+        Some(&byte) if is_digit(byte) => {
+            let mut index: usize = 0;
+            while *position < input.len() && is_digit(input[*position]) {
+                let digit = (input[*position] - b'0') as usize;
+                index = index.checked_mul(10)?.checked_add(digit)?;
+                *position += 1;
+            }
+            Selector::Index(index)
+        }
+        _ => return None,
+    };
+    if input.get(*position) != Some(&b']') {
+        return None;
+    }
+    *position += 1;
+    Some(selector)
+}
+
+fn parse_selector(input: &[u8]) -> Option<Vec<Segment>> {
+    if input.first() != Some(&b'$') {
+        return None;
+    }
+    let mut position = 1;
+    let mut segments = Vec::new();
+    while position < input.len() {
+        let segment = match (input[position], input.get(position + 1)) {
+            (b'.', Some(&b'.')) => {
+                position += 2;
+                let selector = match input.get(position) {
+                    Some(&b'[') => parse_bracket(input, &mut position)?,
+                    _ => parse_name(input, &mut position)?,
+                };
+                Segment { selector: selector, descendant: true }
+            }
+            (b'.', _) => {
+                position += 1;
+                Segment { selector: parse_name(input, &mut position)?, descendant: false }
+            }
+            (b'[', _) => Segment { selector: parse_bracket(input, &mut position)?, descendant: false },
+            _ => return None,
+        };
+        segments.push(segment);
+    }
+    Some(segments)
+}
+
+/// Builds the nodes for a selector.
+///
+/// Every node stands for the set of segments that could apply to the
+/// children of the values matching it, given by the number of segments
+/// already matched. A value is selected when all of the segments have been
+/// matched, and dropped when none of them can apply anymore. Values inside
+/// of a selected value are not selected again.
+struct Compiler<'a> {
+    segments: &'a [Segment],
+    nodes: Vec<Node>,
+    /// Nodes already made for sets of positions, so that recursive descent
+    /// can refer back to them.
+    states: HashMap<Vec<usize>, NodeId>,
+}
+
+impl<'a> Compiler<'a> {
+
+    /// Positions for a child of a value at `positions`, where `matches`
+    /// tells whether a segment picks out the child.
+    fn next<F>(&self, positions: &[usize], matches: F) -> Vec<usize>
+        where F: Fn(&Selector) -> bool {
+
+        let mut next = Vec::new();
+        for &position in positions {
+            let segment = &self.segments[position];
+            if matches(&segment.selector) {
+                next.push(position + 1);
+            }
+            if segment.descendant {
+                next.push(position);
+            }
+        }
+        next.sort();
+        next.dedup();
+        next
+    }
+
+    fn push(&mut self, variant: NodeVariant, options: NodeOptions, parent: NodeId) -> NodeId {
+        let current = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            variant: variant,
+            options: options,
+            parent: Some(parent),
+        });
+        current
+    }
+
+    fn node(&mut self, positions: Vec<usize>, parent: NodeId) -> NodeId {
+        let positions = if positions.contains(&self.segments.len()) {
+            vec![self.segments.len()]
+        } else {
+            positions
+        };
+        if let Some(&node_id) = self.states.get(&positions) {
+            return node_id;
+        }
+
+        if positions.is_empty() {
+            let current = self.push(NodeVariant::Skip, NodeOptions::default(), parent);
+            self.states.insert(positions, current);
+            return current;
+        }
+        if positions[0] == self.segments.len() {
+            let options = NodeOptions {
+                stream: true,
+                stream_collect: true,
+                ..NodeOptions::default()
+            };
+            let current = self.push(NodeVariant::Any, options, parent);
+            self.states.insert(positions, current);
+            return current;
+        }
+
+        let current = self.push(NodeVariant::Sentinel, NodeOptions::default(), parent);
+        self.states.insert(positions.clone(), current);
+
+        let segments = self.segments;
+        let mut keys = HashMap::<Vec<u8>, NodeId>::new();
+        let mut indices = HashMap::<usize, NodeId>::new();
+        for &position in &positions {
+            match segments[position].selector {
+                Selector::Key(ref key) => {
+                    let next = self.next(&positions, |selector| selector.matches_key(key));
+                    let child = self.node(next, current);
+                    keys.insert(key.clone(), child);
+                }
+                Selector::Index(index) => {
+                    let next = self.next(&positions, |selector| selector.matches_index(index));
+                    let child = self.node(next, current);
+                    indices.insert(index, child);
+                }
+                Selector::Wildcard => (),
+            }
+        }
+        let next = self.next(&positions, |selector| selector == &Selector::Wildcard);
+        let other = self.node(next, current);
+
+        self.nodes[current.0].variant = NodeVariant::Select {
+            keys: keys,
+            indices: indices,
+            other_keys: other,
+            other_indices: other,
+        };
+        current
+    }
+
+}
+
+/// Compiles a selector into a spec that streams the values it selects.
+///
+/// Selectors are a subset of JSONPath. They start with `$`, followed by
+/// `.name`, `['name']`, `[index]`, `.*` or `[*]` segments. Any of these can
+/// be preceded by `..` to apply to values at any depth.
+pub fn spec_from_selector(input: &[u8]) -> Option<Spec> {
+    let segments = parse_selector(input)?;
+
+    let sentinel = Node {
+        variant: NodeVariant::Sentinel,
+        options: NodeOptions::default(),
+        parent: None,
+    };
+    let mut compiler = Compiler {
+        segments: &segments,
+        nodes: vec![sentinel],
+        states: HashMap::new(),
+    };
+    let root = compiler.node(vec![0], NodeId(0));
+    assert_eq!(root, NodeId(1));
+
+    Some(Spec {
+        nodes: compiler.nodes,
+        root: NodeId(0),
+    })
+}
@@ -1,5 +1,7 @@
 use super::{Spec, NodeId, ValueType};
 
+use std::sync::Arc;
+
 use ::rustler::{NifEnv, NifTerm, NifEncoder};
 use ::rustler::types::binary::OwnedNifBinary;
 use std::io::Write;
@@ -7,15 +9,17 @@ use std::io::Write;
 #[derive(Debug)]
 pub enum PathEntry {
     Key(Vec<u8>),
-    /// The index field is 1 indexed so that 0 can be used as a sentinel value.
-    /// This is a massive hack and should probably be fixed. I don't like it.
-    Index(usize),
+    /// Index of the current element, `None` in an array that was just
+    /// entered, before its first element is visited.
+    Index(Option<usize>),
 }
 
 impl NifEncoder for PathEntry {
     fn encode<'a>(&self, env: NifEnv<'a>) -> NifTerm<'a> {
         match self {
-            &PathEntry::Index(idx) => ((idx - 1) as u64).encode(env),
+            // Arrays that were just entered are at index 0 before their
+            // first element is visited.
+            &PathEntry::Index(idx) => (idx.unwrap_or(0) as u64).encode(env),
             &PathEntry::Key(ref key) => {
                 let mut bin = OwnedNifBinary::new(key.len()).unwrap();
                 bin.as_mut_slice().write(key).unwrap();
@@ -26,13 +30,29 @@ impl NifEncoder for PathEntry {
 }
 
 impl PathEntry {
+    /// Appends the entry to an RFC 6901 JSON Pointer.
+    pub fn write_pointer(&self, out: &mut Vec<u8>) {
+        out.push(b'/');
+        match self {
+            &PathEntry::Index(idx) => write!(out, "{}", idx.unwrap_or(0)).unwrap(),
+            &PathEntry::Key(ref key) => {
+                for &byte in key {
+                    match byte {
+                        b'~' => out.extend_from_slice(b"~0"),
+                        b'/' => out.extend_from_slice(b"~1"),
+                        _ => out.push(byte),
+                    }
+                }
+            }
+        }
+    }
     pub fn key<'a>(&'a self) -> &'a [u8] {
         match self {
             &PathEntry::Key(ref data) => &data,
             _ => unreachable!(),
         }
     }
-    pub fn index(&self) -> usize {
+    pub fn index(&self) -> Option<usize> {
         match self {
             &PathEntry::Index(index) => index,
             _ => unreachable!(),
@@ -42,60 +62,123 @@ impl PathEntry {
 
 #[derive(Debug)]
 pub struct SpecWalker {
-    pub spec: Spec,
+    pub spec: Arc<Spec>,
     current: NodeId,
-    depth: usize,
+    /// Nodes of the values we are inside of, innermost last. Kept here
+    /// rather than followed through `Node::parent`, since selector specs
+    /// use the same node at several depths.
+    parents: Vec<NodeId>,
     height_off_current: usize,
+    /// Node chosen by the tag of a tag array, used for the element after
+    /// the tag. Along with the depth of the tag array, so that it is only
+    /// used for that array.
+    selected: Option<(NodeId, usize)>,
 }
 
+#[derive(Copy, Clone)]
 pub struct PathPosition {
     pub current: Option<NodeId>,
     pub parent: Option<NodeId>,
+    /// The node the spec expected, if the value did not match it.
+    pub mismatch: Option<NodeId>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyAction {
+    Keep,
+    Ignore,
+    Error,
+    /// The key is unknown and we are in strict mode.
+    Mismatch,
+}
+
+pub struct KeyPosition {
+    /// The map node the key belongs to, if it is inside the spec.
+    pub parent: Option<NodeId>,
+    pub action: KeyAction,
 }
 
 impl SpecWalker {
-    pub fn new(spec: Spec) -> SpecWalker {
+    pub fn new(spec: Arc<Spec>) -> SpecWalker {
         SpecWalker {
             current: spec.root_id(),
             spec: spec,
 
-            depth: 0,
+            parents: Vec::new(),
             height_off_current: 0,
+            selected: None,
+        }
+    }
+
+    /// The node we are currently in, if we are inside the spec.
+    pub fn current(&self) -> Option<NodeId> {
+        if self.height_off_current == 0 {
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
+    /// Moves from the current node to a sibling node. Used by matchers
+    /// once they have decided what node a value of type `typ` should be
+    /// parsed as. Type matchers are resolved the same way as in `try_child`,
+    /// one without a mapping for `typ` stays current and has no children.
+    pub fn replace_current(&mut self, node: NodeId, typ: ValueType) {
+        debug_assert_eq!(self.height_off_current, 0);
+        self.current = self.spec.resolve_type(node, typ).unwrap_or(node);
+    }
+
+    /// Selects the node to use for the value of the tag array we are in.
+    pub fn select_child(&mut self, node: NodeId) {
+        self.selected = Some((node, self.parents.len()));
+    }
+
+    /// Whether a node was selected by the tag array we are in.
+    fn selected_here(&self) -> bool {
+        match self.selected {
+            Some((_, depth)) => depth == self.parents.len(),
+            None => false,
         }
     }
 
-    fn try_child(&self, typ: ValueType, key: Option<&PathEntry>) -> PathPosition {
+    fn try_child(&mut self, typ: ValueType, key: Option<&PathEntry>) -> PathPosition {
         match self.height_off_current {
             0 => {
                 let current = self.spec.get(self.current);
 
                 let child_node_id = match key {
                     None => current.variant.child_root(),
-                    Some(&PathEntry::Index(index)) => current.variant.child_index(index),
+                    Some(&PathEntry::Index(Some(1))) if self.selected_here() =>
+                        self.selected.take().map(|(node, _)| node),
+                    Some(&PathEntry::Index(index)) => current.variant.child_index(index.unwrap_or(0)),
                     Some(&PathEntry::Key(ref name)) => current.variant.child_key(&name),
                 };
 
-                let child_node_match = match child_node_id {
+                let (child_node_match, mismatch) = match child_node_id {
                     Some(child_id) => {
-                        let child = self.spec.get(child_id);
-                        if child.variant.matches(typ) {
-                            Some(child_id)
-                        } else {
-                            None
+                        // The type is already known, so type matchers are
+                        // resolved right away.
+                        let resolved_id = self.spec.resolve_type(child_id, typ);
+                        match resolved_id {
+                            Some(resolved_id) if self.spec.get(resolved_id).variant.matches(typ) =>
+                                (Some(resolved_id), None),
+                            _ => (None, Some(child_id)),
                         }
                     }
-                    None => None,
+                    None => (None, None),
                 };
 
                 PathPosition {
                     current: child_node_match,
                     parent: Some(self.current),
+                    mismatch: mismatch,
                 }
             }
             _ => {
                 PathPosition {
                     current: None,
                     parent: None,
+                    mismatch: None,
                 }
             }
         }
@@ -108,11 +191,30 @@ impl SpecWalker {
         self.try_child(typ, key)
     }
 
-    pub fn visit_key(&self) -> Option<NodeId> {
+    pub fn visit_key(&self, key: &[u8]) -> KeyPosition {
         if self.height_off_current == 0 {
-            Some(self.current)
+            let current = self.spec.get(self.current);
+            let mut action = current.variant.key_action(key, current.options.strict);
+            if action == KeyAction::Keep {
+                action = current.options.non_atom_action(key);
+            }
+            // Pairs with values that are skipped are dropped as a whole.
+            if action == KeyAction::Keep {
+                if let Some(child_id) = current.variant.child_key(key) {
+                    if self.spec.get(child_id).variant.drops_value() {
+                        action = KeyAction::Ignore;
+                    }
+                }
+            }
+            KeyPosition {
+                parent: Some(self.current),
+                action: action,
+            }
         } else {
-            None
+            KeyPosition {
+                parent: None,
+                action: KeyAction::Keep,
+            }
         }
     }
 
@@ -124,8 +226,8 @@ impl SpecWalker {
 
         match resp.current {
             Some(child_id) => {
+                self.parents.push(self.current);
                 self.current = child_id;
-                self.depth += 1;
             }
             None => {
                 self.height_off_current += 1;
@@ -138,12 +240,16 @@ impl SpecWalker {
     pub fn exit_nonterminal<'a>(&'a mut self) -> PathPosition {
         match self.height_off_current {
             0 => {
+                // A tag array without an element after its tag.
+                if self.selected_here() {
+                    self.selected = None;
+                }
                 let current_id = self.current;
-                self.current = self.spec.get(current_id).parent.unwrap();
-                self.depth -= 1;
+                self.current = self.parents.pop().unwrap();
                 PathPosition {
                     current: Some(current_id),
                     parent: Some(self.current),
+                    mismatch: None,
                 }
             }
             1 => {
@@ -151,6 +257,7 @@ impl SpecWalker {
                 PathPosition {
                     current: None,
                     parent: Some(self.current),
+                    mismatch: None,
                 }
             }
             _ => {
@@ -158,6 +265,7 @@ impl SpecWalker {
                 PathPosition {
                     current: None,
                     parent: None,
+                    mismatch: None,
                 }
             }
         }
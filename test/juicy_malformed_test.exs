@@ -0,0 +1,125 @@
+defmodule JuicyMalformedTest do
+  use ExUnit.Case
+
+  # Every parse function should return an error for malformed input, never
+  # raise from the NIF.
+
+  @valid ~s({"a": [1, -2.5e3, "x\\n\\u00E5", true, null], "b": {"c": [], "": {}}})
+
+  @malformed [
+    "",
+    " ",
+    "{",
+    "[",
+    "]",
+    "}",
+    "[1,]",
+    "{\"a\"}",
+    "{\"a\": }",
+    "{\"a\" 1}",
+    "{1: 2}",
+    "[1 2]",
+    "[\"abc]",
+    "[\"\\u12\"]",
+    "[\"\\q\"]",
+    "[1.]",
+    "[-]",
+    "[tru]",
+    "[nul]",
+    "[1e400]",
+    "[-1e400]",
+    <<"[\"", 0xC3, "\"]">>,
+    <<"{\"", 0xFF, "\": 1}">>,
+    <<0xEF, 0xBB, 0xBF, "[]">>,
+    <<"[", 0, "]">>,
+    String.duplicate("[", 2000),
+    String.duplicate("{\"a\":", 2000),
+    "[1] [2]",
+    "{} x",
+  ]
+
+  # Matchers pick the node to use from the input, which takes different
+  # paths through the parsers.
+  @matchers [
+    {:tagged_map, [key_name: "a", mappings: %{"x" => {:any, []}}, default: {:node, {:any, []}}]},
+    {:tagged_map, [key_name: "a", mappings: %{"x" => {:any, []}}]},
+    {:array, [], {:tag_array, [mappings: %{"x" => {:any, []}}, default: :ignore]}},
+    {:tag_array, [mappings: %{"x" => {:any, []}}]},
+    {:type, [type_mappings: %{map: {:map, [], {:type, [type_mappings: %{array: {:any, []}}]}},
+                              array: {:any, []}}]},
+  ]
+
+  def parse_all(input) do
+    [
+      Juicy.parse(input),
+      Juicy.parse(input, keys: :atoms),
+      Juicy.parse(input, keys: :existing_atoms),
+      Juicy.parse_spec(input, {:any, []}),
+      Juicy.parse_spec(input, {:map_keys, [strict: true], %{"a" => {:array, [], {:any, []}}}}),
+    ] ++ Enum.map(@matchers, &Juicy.parse_spec(input, &1))
+  end
+
+  def stream_all(input) do
+    for spec <- [{:any, [stream: true]} | @matchers], size <- [1, 3, 7, byte_size(input) + 1] do
+      input
+      |> chunks(size)
+      |> Juicy.parse_stream(spec)
+      |> Enum.into([])
+    end
+  end
+
+  def chunks(binary, size) when byte_size(binary) <= size, do: [binary]
+  def chunks(binary, size) do
+    <<chunk::binary-size(size), rest::binary>> = binary
+    [chunk | chunks(rest, size)]
+  end
+
+  def ok_or_error?({:ok, _}), do: true
+  def ok_or_error?({:error, %Juicy.Error{}}), do: true
+  def ok_or_error?(_), do: false
+
+  test "malformed input returns errors" do
+    for input <- @malformed do
+      for result <- parse_all(input) do
+        assert match?({:error, %Juicy.Error{}}, result), "for #{inspect input}"
+      end
+      for out <- stream_all(input) do
+        assert Enum.any?(out, &match?({:error, _}, &1)), "for #{inspect input}"
+      end
+    end
+  end
+
+  test "numbers out of range" do
+    assert {:error, %Juicy.Error{reason: :invalid_number, detail: :out_of_range, path: [0]}} =
+      Juicy.parse("[1e400]")
+    assert {:error, %Juicy.Error{reason: :invalid_number, detail: :out_of_range, path: ["a"]}} =
+      Juicy.parse_spec(~s({"a": -1e400}), {:any, []})
+  end
+
+  test "truncated input returns errors" do
+    for length <- 0..(byte_size(@valid) - 1) do
+      input = binary_part(@valid, 0, length)
+      for result <- parse_all(input) do
+        assert match?({:error, %Juicy.Error{}}, result), "for #{inspect input}"
+      end
+    end
+  end
+
+  test "corrupted input never raises" do
+    :rand.seed(:exsplus, {1, 2, 3})
+    bytes = '{}[]":,\\e.-0u \n' ++ [0, 0x80, 0xC3, 0xFF]
+
+    for _ <- 1..500 do
+      position = :rand.uniform(byte_size(@valid)) - 1
+      byte = Enum.random(bytes)
+      <<head::binary-size(position), _, tail::binary>> = @valid
+      input = <<head::binary, byte, tail::binary>>
+
+      for result <- parse_all(input) do
+        assert ok_or_error?(result), "for #{inspect input}"
+      end
+      stream_all(input)
+    end
+  end
+
+end
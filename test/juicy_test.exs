@@ -8,6 +8,9 @@ defmodule JuicyTest do
 
   def p(binary), do: Juicy.parse(binary)
 
+  def err({:error, %Juicy.Error{reason: reason, path: path, detail: detail}}),
+    do: {reason, path, detail}
+
   test "empty objects" do
     assert p("{}") == {:ok, %{}}
     assert p("[]") == {:ok, []}
@@ -43,6 +46,18 @@ defmodule JuicyTest do
     assert p(input) == output
   end
 
+  test "atom keys" do
+    input = ~s({"juicy_test_key": {"b": 1}})
+    assert Juicy.parse(input, keys: :atoms) == {:ok, %{juicy_test_key: %{b: 1}}}
+    assert Juicy.parse(input, keys: :existing_atoms) == {:ok, %{juicy_test_key: %{b: 1}}}
+    assert Juicy.parse(input, keys: :strings) == {:ok, %{"juicy_test_key" => %{"b" => 1}}}
+
+    input = ~s({"a": {"juicy_test_no_such_atom": 1}})
+    assert err(Juicy.parse(input, keys: :existing_atoms)) ==
+      {:invalid_key, ["a"], "juicy_test_no_such_atom"}
+    assert err(Juicy.parse(~s({"\u00E5": 1}), keys: :atoms)) == {:invalid_key, [], "\u00E5"}
+  end
+
   test "match spec validation" do
     assert :ok == Juicy.validate_spec({:map, [], {:any, []}})
     assert :ok == Juicy.validate_spec({:map, [], {:any, [stream: true]}})
@@ -72,7 +87,231 @@ defmodule JuicyTest do
     spec = {:any, []}
     out = Juicy.parse_stream(input, spec) |> Enum.into([])
 
-    assert out == [error: :early_eoi]
+    assert [{:error, %Juicy.Error{reason: :unexpected_eof, position: 1, path: []}}] = out
+
+    input = [~s({"a": [1, ), ~s("b)]
+    out = Juicy.parse_stream(input, spec) |> Enum.into([])
+    assert [{:error, %Juicy.Error{reason: :unexpected_eof, position: 12, path: ["a", 1]}}] = out
+  end
+
+  test "end of input stream" do
+    spec = {:any, [stream: true]}
+    assert Juicy.parse_stream(["12", "3"], spec) |> Enum.into([]) ==
+      [{:yield, {[], 123}}, :finished]
+    assert Juicy.parse_stream(["[1] ", "\n"], spec) |> Enum.into([]) ==
+      [{:yield, {[], [1]}}, :finished]
+
+    out = Juicy.parse_stream(["[1] ", " x"], spec) |> Enum.into([])
+    assert [{:yield, {[], [1]}}, {:error, %Juicy.Error{reason: :trailing_data, position: 5}}] = out
+    out = Juicy.parse_stream(["1 2"], spec) |> Enum.into([])
+    assert [{:yield, {[], 1}}, {:error, %Juicy.Error{reason: :trailing_data, position: 2}}] = out
+  end
+
+  test "ndjson stream" do
+    input = [~s({"a": 1}\n), "\n", ~s([1, 2]\r\n), "12", "3\n", ~s({"a": }\n), "x\n", ~s(1 2\n), ~s("last")]
+    out = Juicy.parse_stream(input, {:any, []}, format: :ndjson) |> Enum.into([])
+
+    assert [
+      {:yield, {[0], %{"a" => 1}}},
+      {:yield, {[2], [1, 2]}},
+      {:yield, {[3], 123}},
+      {:error, %Juicy.Error{reason: :unexpected_character, path: [4, "a"], line: 5}},
+      {:error, %Juicy.Error{reason: :unexpected_character, path: [5]}},
+      {:yield, {[6], 1}},
+      {:error, %Juicy.Error{reason: :trailing_data, path: [6]}},
+      {:yield, {[7], "last"}},
+      :finished,
+    ] = out
+
+    spec = {:map, [], {:any, [stream: true]}}
+    out = Juicy.parse_stream([~s({"a": 1}\n{"b"), ~s(: 2}\n{\n}\n)], spec, format: :ndjson) |> Enum.into([])
+    assert [
+      {:yield, {[0, "a"], 1}},
+      {:yield, {[0], %{"a" => :streamed}}},
+      {:yield, {[1, "b"], 2}},
+      {:yield, {[1], %{"b" => :streamed}}},
+      {:error, %Juicy.Error{reason: :unexpected_eof, path: [2]}},
+      {:error, %Juicy.Error{reason: :unexpected_character, path: [3]}},
+      :finished,
+    ] = out
+
+    assert Juicy.parse_stream(["\n", " \r\n"], {:any, []}, format: :ndjson) |> Enum.into([]) ==
+      [:finished]
+
+    # Records streamed by the spec are not yielded again.
+    out = Juicy.parse_stream([~s(1\n[2]\n)], {:any, [stream: true]}, format: :ndjson)
+    |> Enum.into([])
+    assert out == [{:yield, {[0], 1}}, {:yield, {[1], [2]}}, :finished]
+  end
+
+  test "json-seq and concatenated streams" do
+    input = ["\x1E{\"a\": 1}\n\x1E", "[1, 2\n\x1E\x1E\"b\"\n", "\x1E1", "2 x\n\x1E3"]
+    out = Juicy.parse_stream(input, {:any, []}, format: :json_seq, offsets: true) |> Enum.into([])
+    assert [
+      {:record, 0, 1},
+      {:yield, {[0], %{"a" => 1}}},
+      {:record, 1, 11},
+      {:error, %Juicy.Error{reason: :unexpected_eof, path: [1, 1], position: 17}},
+      {:record, 2, 19},
+      {:yield, {[2], "b"}},
+      {:record, 3, 24},
+      {:yield, {[3], 12}},
+      {:error, %Juicy.Error{reason: :trailing_data, path: [3], position: 27}},
+      {:record, 4, 30},
+      {:yield, {[4], 3}},
+      :finished,
+    ] = out
+
+    input = [~s({"a": 1}[1), ~s(, 2] "b"1 2\n), "true"]
+    out = Juicy.parse_stream(input, {:any, []}, format: :concatenated) |> Enum.into([])
+    assert out == [
+      {:yield, {[0], %{"a" => 1}}},
+      {:yield, {[1], [1, 2]}},
+      {:yield, {[2], "b"}},
+      {:yield, {[3], 1}},
+      {:yield, {[4], 2}},
+      {:yield, {[5], true}},
+      :finished,
+    ]
+
+    out = Juicy.parse_stream([~s({"a": 1} x {"b": 2})], {:any, []}, format: :concatenated)
+    |> Enum.into([])
+    assert [{:yield, {[0], %{"a" => 1}}}, {:error, %Juicy.Error{position: 9}}] = out
+  end
+
+  def select(input, selector, opts \\ []) do
+    Juicy.stream([input], [select: selector] ++ opts) |> Enum.into([])
+  end
+
+  test "selectors" do
+    input = ~s({"data": {"items": [1, {"a": 2}], "other": [3]}, "items": [4]})
+    assert select(input, "$.data.items[*]") == [
+      {:yield, {["data", "items", 0], 1}},
+      {:yield, {["data", "items", 1], %{"a" => 2}}},
+      :finished,
+    ]
+    assert select(input, "$.data.items[1].a") == [{:yield, {["data", "items", 1, "a"], 2}}, :finished]
+    assert select(input, "$") == [{:yield, {[], Juicy.parse(input) |> elem(1)}}, :finished]
+    assert select(~s({"a.b": [1, 2, 3]}), "$['a.b'][1]") == [{:yield, {["a.b", 1], 2}}, :finished]
+    assert select(~s({"a": 1, "b": [2]}), "$.*") ==
+      [{:yield, {["a"], 1}}, {:yield, {["b"], [2]}}, :finished]
+    assert select(~s({"a": 1}), "$.b") == [:finished]
+  end
+
+  test "recursive descent selectors" do
+    input = ~s({"id": 1, "x": [{"id": 2}, {"y": {"id": {"id": 3}}}]})
+    assert select(input, "$..id") == [
+      {:yield, {["id"], 1}},
+      {:yield, {["x", 0, "id"], 2}},
+      {:yield, {["x", 1, "y", "id"], %{"id" => 3}}},
+      :finished,
+    ]
+    assert select(~s({"a": {"a": {"b": 1}, "b": 2}}), "$..a.b") ==
+      [{:yield, {["a", "a", "b"], 1}}, {:yield, {["a", "b"], 2}}, :finished]
+    assert select(~s([[1, [2]], 3]), "$..[0]") ==
+      [{:yield, {[0], [1, [2]]}}, :finished]
+  end
+
+  test "selectors with stream options" do
+    input = ~s({"a": 1}\n{"b": 2}\n{"a": [3]}\n)
+    assert select(input, "$.a", format: :ndjson) ==
+      [{:yield, {[0, "a"], 1}}, {:yield, {[2, "a"], [3]}}, :finished]
+    assert select(input, "$.a[0]", format: :ndjson, path: :pointer) ==
+      [{:yield, {"/2/a/0", 3}}, :finished]
+  end
+
+  test "invalid selectors" do
+    for selector <- ["", "data", "$.", "$..", "$[", "$[x]", "$['a]", "$[1", "$a"] do
+      assert_raise ArgumentError, fn -> select("{}", selector) end
+    end
+  end
+
+  test "json pointer paths" do
+    spec = {:map, [], {:array, [], {:any, [stream: true]}}}
+    input = ~s({"a/b": [1, 2], "~c": [], "": [{}], "d": [x]})
+    out = Juicy.parse_stream([input], spec, path: :pointer) |> Enum.into([])
+    assert [
+      {:yield, {"/a~1b/0", 1}},
+      {:yield, {"/a~1b/1", 2}},
+      {:yield, {"//0", %{}}},
+      {:error, %Juicy.Error{reason: :unexpected_character, path: "/d/0"}},
+    ] = out
+
+    out = Juicy.parse_stream([~s({"~": 1}
+[2]
+)], {:any, []}, format: :ndjson, path: :pointer)
+    |> Enum.into([])
+    assert [{:yield, {"/0", %{"~" => 1}}}, {:yield, {"/1", [2]}}, :finished] = out
+
+    spec = {:map, [], {:any, [stream: true]}}
+    out = Juicy.parse_stream([~s({"~": 1})], spec, format: :ndjson, path: :pointer)
+    |> Enum.into([])
+    assert [{:yield, {"/0/~0", 1}}, {:yield, {"/0", %{"~" => :streamed}}}, :finished] = out
+
+    out = Juicy.parse_stream(["[[1]]"], {:any, [stream: true]}, path: :pointer) |> Enum.into([])
+    assert [{:yield, {"", [[1]]}}, :finished] = out
+  end
+
+  test "error locations" do
+    input = ~s({\n  "a": 1,\n  "b": x\n})
+    assert {:error, %Juicy.Error{reason: :unexpected_character, position: 19, line: 3,
+                                 column: 8, excerpt: ~s(  "b": x)}} = p(input)
+
+    input = ~s({"å": [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, ]})
+    assert {:error, %Juicy.Error{position: 63, line: 1, column: 63, excerpt: excerpt}} = p(input)
+    assert excerpt == ~s(12, 13, 14, 15, 16, ]})
+
+    assert {:error, %Juicy.Error{position: 19, line: 3, column: 8, path: ["b"]}} =
+      Juicy.parse_spec(~s({\n  "a": 1,\n  "b": x\n}), {:any, []})
+
+    input = ["[1,\n", "2,\n", "x]"]
+    assert [{:error, %Juicy.Error{position: 7, line: 3, column: 1, excerpt: "x]"}}] =
+      Juicy.parse_stream(input, {:array, [], {:any, [stream: true]}})
+      |> Enum.filter(&match?({:error, _}, &1))
+
+    input = for <<byte <- ~s([1, "é", 3, x])>>, do: <<byte>>
+    assert [{:error, %Juicy.Error{position: 13, line: 1, column: 13, excerpt: ~s([1, "é", 3, x])}}] =
+      Juicy.parse_stream(input, {:array, [], {:any, [stream: true]}})
+      |> Enum.filter(&match?({:error, _}, &1))
+
+    assert {:error, %Juicy.Error{reason: :invalid_utf8, position: 4}} = p(<<"[\"\\n", 0xFF, "\"]">>)
+    assert {:error, %Juicy.Error{reason: :invalid_utf8, position: 4}} =
+      Juicy.parse_spec(<<"[\"\\n", 0xFF, "\"]">>, {:any, []})
+
+    spec = {:any, [stream: true]}
+    assert Juicy.parse_stream(["[\"", <<0xC3>>, <<0xA9, "\"]">>], spec) |> Enum.into([]) ==
+      [{:yield, {[], ["é"]}}, :finished]
+    assert [{:error, %Juicy.Error{reason: :invalid_utf8, position: 4}}] =
+      Juicy.parse_stream(["[\"é", <<0xE2, 0x82>>, "\"]"], spec) |> Enum.into([])
+    assert [{:error, %Juicy.Error{reason: :invalid_utf8, position: 5}}] =
+      Juicy.parse_stream(["[\"\\n", "a", <<0xFF, "\"]">>], spec) |> Enum.into([])
+  end
+
+  test "error reasons" do
+    assert err(p("[1, 2")) == {:unexpected_eof, [1], nil}
+    assert err(p(~s(["\\x"]))) == {:invalid_escape, [0], nil}
+    assert err(p(~s(["\\u12x"]))) == {:invalid_escape, [0], nil}
+    assert {:unexpected_character, _, _} = err(p(~s(["\\\\", x])))
+    assert err(p(<<"[\"", 0xFF, "\"]">>)) == {:invalid_utf8, [0], nil}
+    assert err(p("[1] x")) == {:trailing_data, [], nil}
+    assert {:depth_limit, _, 1024} = err(p(String.duplicate("[", 1025)))
+
+    assert err(Juicy.parse_spec("[1] x", {:any, []})) == {:trailing_data, [], nil}
+    assert {:invalid_utf8, _, nil} = err(Juicy.parse_spec(<<"{\"", 0xFF, "\": 1}">>, {:any, []}))
+    assert {:depth_limit, _, 1024} =
+      err(Juicy.parse_spec(String.duplicate("[", 1025), {:any, []}))
+
+    assert {:depth_limit, _, 2} = err(Juicy.parse("[[[1]]]", max_depth: 2))
+    assert {:ok, [[1]]} = Juicy.parse("[[1]]", max_depth: 2)
+    assert {:depth_limit, _, 2} = err(Juicy.parse_spec("[[[1]]]", {:any, []}, max_depth: 2))
+    deep = String.duplicate("[", 2000) <> String.duplicate("]", 2000)
+    assert {:ok, _} = Juicy.parse(deep, max_depth: :infinity)
+    assert {:ok, _} = Juicy.parse_spec(deep, {:any, []}, max_depth: 4000)
+    assert [{:error, %Juicy.Error{reason: :depth_limit, detail: 2}}] =
+      Juicy.parse_stream(["[[[1]]]"], {:any, []}, max_depth: 2) |> Enum.into([])
+
+    {:error, error} = p("[1] x")
+    assert Exception.message(error) == "trailing_data at line 1, column 5"
   end
 
   test "json parsing with simple spec" do
@@ -94,4 +333,380 @@ defmodule JuicyTest do
                     ]}
   end
 
+  test "json parsing with map_keys spec" do
+    input = ~s({"a": [1], "b": {"c": 2}})
+    spec = {:map_keys, [], %{"a" => {:array, [], {:any, []}}, "b" => {:map, [atom_keys: [:c]], {:any, []}}}}
+    assert Juicy.parse_spec(input, spec) == {:ok, %{"a" => [1], "b" => %{c: 2}}}
+
+    spec = {:map_keys, [unknown_keys: :ignore], %{"a" => {:any, []}}}
+    assert Juicy.parse_spec(input, spec) == {:ok, %{"a" => [1]}}
+
+    spec = {:map_keys, [unknown_keys: :error], %{"a" => {:any, []}}}
+    assert err(Juicy.parse_spec(input, spec)) == {:unknown_key, ["b"], nil}
+
+    spec = {:map_keys, [unknown_keys: {:node, {:map, [atom_keys: [:c]], {:any, []}}}], %{"a" => {:any, []}}}
+    assert Juicy.parse_spec(input, spec) == {:ok, %{"a" => [1], "b" => %{c: 2}}}
+  end
+
+  test "stream with map_keys spec" do
+    input = [~s({"a": 1, "b": 2, "c": 3})]
+    spec = {:map_keys, [unknown_keys: :ignore], %{"a" => {:any, [stream: true]}, "b" => {:any, []}}}
+    out = Juicy.parse_stream(input, spec) |> Enum.into([])
+
+    assert out == [
+      {:yield, {["a"], 1}},
+      {:yield, {[], %{"a" => :streamed, "b" => 2}}},
+      :finished,
+    ]
+  end
+
+  test "json parsing with non atom keys" do
+    input = ~s({"a": 1, "b": {"c": 2}})
+    spec = {:map, [atom_keys: [:a]], {:any, []}}
+    assert Juicy.parse_spec(input, spec) == {:ok, %{:a => 1, "b" => %{"c" => 2}}}
+
+    spec = {:map, [atom_keys: [:a], ignore_non_atoms: true], {:any, []}}
+    assert Juicy.parse_spec(input, spec) == {:ok, %{a: 1}}
+
+    spec = {:map, [atom_keys: [:a], reject_non_atoms: true], {:any, []}}
+    assert err(Juicy.parse_spec(input, spec)) == {:unknown_key, ["b"], nil}
+
+    spec = {:map_keys, [struct_atom: URI, atom_keys: [:host], ignore_non_atoms: true],
+            %{"host" => {:any, []}, "other" => {:any, []}}}
+    assert Juicy.parse_spec(~s({"host": "a", "other": 1, "extra": 2}), spec) ==
+      {:ok, %{__struct__: URI, host: "a"}}
+
+    # Streaming builds the same keys and structs.
+    spec = {:array, [], {:map, [atom_keys: [:a], stream: true], {:any, []}}}
+    assert Juicy.parse_stream(["[", input, "]"], spec) |> Enum.into([]) ==
+      [{:yield, {[0], %{:a => 1, "b" => %{"c" => 2}}}}, :finished]
+    spec = {:map_keys, [struct_atom: URI, atom_keys: [:host], ignore_non_atoms: true, stream: true],
+            %{"host" => {:any, []}, "other" => {:any, []}}}
+    assert Juicy.parse_stream([~s({"host": "a", "other": 1})], spec) |> Enum.into([]) ==
+      [{:yield, {[], %{__struct__: URI, host: "a"}}}, :finished]
+  end
+
+  test "json parsing with tuple spec" do
+    spec = {:array, [], {:tuple, [as_tuple: true], [{:any, []}, {:map, [atom_keys: [:a]], {:any, []}}]}}
+    assert Juicy.parse_spec(~s([[1, {"a": 2}], [3, {}]]), spec) == {:ok, [{1, %{a: 2}}, {3, %{}}]}
+    assert err(Juicy.parse_spec(~s([[1, {}], [2]]), spec)) == {:tuple_length, [1], {2, 1}}
+
+    spec = {:tuple, [], [{:any, []}, {:any, []}]}
+    assert Juicy.parse_spec(~s([1, 2]), spec) == {:ok, [1, 2]}
+    assert err(Juicy.parse_spec(~s([1, 2, 3]), spec)) == {:tuple_length, [], {2, 3}}
+  end
+
+  test "json parsing with enum spec" do
+    spec = {:array, [], {:enum, [values: %{"active" => :active, "suspended" => :suspended}]}}
+    assert Juicy.parse_spec(~s(["active", "suspended"]), spec) == {:ok, [:active, :suspended]}
+    assert err(Juicy.parse_spec(~s(["active", "deleted"]), spec)) == {:invalid_enum, [1], "deleted"}
+    assert err(Juicy.parse_spec(~s(["active", 5]), spec)) == {:spec_mismatch, [1], {:string, :number}}
+    assert err(Juicy.parse_spec(~s([{}]), spec)) == {:spec_mismatch, [0], {:string, :map}}
+    assert [{:error, %Juicy.Error{reason: :spec_mismatch, path: [1]}}] =
+      Juicy.parse_stream([~s(["active", null])], spec) |> Enum.into([])
+
+    spec = {:map, [], {:enum, [values: ["on", "off"]]}}
+    assert Juicy.parse_spec(~s({"a": "on", "b": "off"}), spec) == {:ok, %{"a" => :on, "b" => :off}}
+  end
+
+  test "json parsing with static spec" do
+    spec = {:map_keys, [], %{"id" => {:any, []}, "blob" => {:static, [value: :omitted]}}}
+    assert Juicy.parse_spec(~s({"id": 1, "blob": {"data": [1, "2", {"x": null}]}}), spec) ==
+      {:ok, %{"id" => 1, "blob" => :omitted}}
+    assert Juicy.parse_spec(~s({"id": 1, "blob": "abc"}), spec) ==
+      {:ok, %{"id" => 1, "blob" => :omitted}}
+
+    spec = {:array, [], {:static, [value: {:some, "term"}]}}
+    assert Juicy.parse_spec(~s([[], 1]), spec) == {:ok, [{:some, "term"}, {:some, "term"}]}
+  end
+
+  test "json parsing with skip spec" do
+    spec = {:map_keys, [], %{"id" => {:any, []}, "blob" => {:skip, []}}}
+    assert Juicy.parse_spec(~s({"id": 1, "blob": {"data": [1, "2", {"x": null}]}}), spec) ==
+      {:ok, %{"id" => 1}}
+    assert Juicy.parse_spec(~s({"blob": "abc", "id": 1}), spec) == {:ok, %{"id" => 1}}
+    assert Juicy.parse_spec(~s({"id": 1, "blob": {"a": }}), spec) |> elem(0) == :error
+
+    spec = {:array, [], {:skip, []}}
+    assert Juicy.parse_spec(~s([[1], {"a": 2}, "3"]), spec) == {:ok, []}
+
+    spec = {:tuple, [], [{:static, [value: nil]}, {:any, []}]}
+    assert Juicy.parse_spec(~s([{"a": "b"}, 2]), spec) == {:ok, [nil, 2]}
+
+    spec = {:map_keys, [unknown_keys: :ignore], %{"a" => {:any, []}}}
+    assert Juicy.parse_spec(~s({"b": {"c": ["d"]}, "a": 1, "e": "f"}), spec) == {:ok, %{"a" => 1}}
+
+    assert Juicy.compile_spec({:skip, []}) == :error
+    assert Juicy.compile_spec({:type, [type_mappings: %{string: {:skip, []}}]}) == :error
+    assert Juicy.compile_spec({:tuple, [], [{:skip, []}, {:any, []}]}) == :error
+    assert Juicy.compile_spec({:tag_array, [mappings: %{"a" => {:skip, []}}]}) == :error
+  end
+
+  test "json parsing with tagged_map spec" do
+    spec = {:array, [], {:tagged_map, [
+      key_name: "type",
+      mappings: %{
+        "click" => {:map_keys, [atom_keys: [:type, :x]], %{"type" => {:any, []}, "x" => {:any, []}}},
+        "key" => {:map_keys, [], %{"type" => {:any, []}, "code" => {:enum, [values: ["enter"]]}}},
+      },
+    ]}}
+    input = ~s([{"type": "click", "x": 1}, {"code": "enter", "type": "key"}])
+    assert Juicy.parse_spec(input, spec) ==
+      {:ok, [%{type: "click", x: 1}, %{"type" => "key", "code" => :enter}]}
+
+    assert err(Juicy.parse_spec(~s([{"type": "scroll"}]), spec)) == {:unknown_tag, [0], "scroll"}
+    assert err(Juicy.parse_spec(~s([{"x": 1}]), spec)) == {:missing_tag, [0], nil}
+
+    spec = {:tagged_map, [key_name: "t", mappings: %{"a" => {:static, [value: :a]}}, default: :ignore]}
+    assert Juicy.parse_spec(~s({"x": [1, {"t": 2}], "t": "a"}), spec) == {:ok, :a}
+    assert Juicy.parse_spec(~s({"x": 1, "t": "b"}), spec) == {:ok, %{"x" => 1, "t" => "b"}}
+
+    # Tags can map to type matchers, which may be nested.
+    spec = {:tagged_map, [
+      key_name: "t",
+      mappings: %{
+        "a" => {:type, [type_mappings: %{map: {:map_keys, [], %{"x" => {:enum, [values: ["y"]]}}}}]},
+        "b" => {:type, [type_mappings: %{array: {:any, []}}]},
+      },
+      default: {:node, {:type, [type_mappings: %{
+        map: {:type, [type_mappings: %{map: {:static, [value: :other]}}]},
+      }]}},
+    ]}
+    assert Juicy.parse_spec(~s({"t": "a", "x": "y"}), spec) == {:ok, %{"t" => "a", "x" => :y}}
+    assert Juicy.parse_spec(~s({"t": "b", "x": [1]}), spec) == {:ok, %{"t" => "b", "x" => [1]}}
+    assert Juicy.parse_spec(~s({"t": "c", "x": 1}), spec) == {:ok, :other}
+
+    spec = {:tagged_map, [key_name: "t", mappings: %{
+      "a" => {:type, [type_mappings: %{map: {:map, [stream: true], {:any, []}}}]},
+    }]}
+    assert Juicy.parse_stream([~s({"x": 1, ), ~s("t": "a"})], spec) |> Enum.into([]) ==
+      [{:yield, {[], %{"t" => "a", "x" => 1}}}, :finished]
+
+    # Looking for the tag takes more than one slice.
+    long = String.duplicate("x", 100_000)
+    assert Juicy.parse_spec(~s({"x": ["#{long}", {"t": 1}], "t": "a"}), spec) == {:ok, :a}
+  end
+
+  test "stream with tagged_map spec" do
+    spec = {:tagged_map, [key_name: "type", mappings: %{"a" => {:map, [stream: true], {:any, []}}}]}
+    out = Juicy.parse_stream([~s({"x": 1, "ty), ~s(pe": "a"})], spec) |> Enum.into([])
+
+    assert out == [
+      {:yield, {[], %{"x" => 1, "type" => "a"}}},
+      :finished,
+    ]
+
+    # The input before the tag is spread over many chunks.
+    long = String.duplicate("x", 100_000)
+    input = ~s({"x": ["#{long}", {"y": "]"}], "type": "a"})
+    chunks = for start <- 0..div(byte_size(input) - 1, 1000) do
+      binary_part(input, start * 1000, min(1000, byte_size(input) - start * 1000))
+    end
+    out = Juicy.parse_stream(chunks, spec) |> Enum.into([])
+
+    assert out == [
+      {:yield, {[], %{"x" => [long, %{"y" => "]"}], "type" => "a"}}},
+      :finished,
+    ]
+
+    input = ~s({"a": [1,\n 2 x], "type": "a"})
+    chunks = for <<byte <- input>>, do: <<byte>>
+    assert [{:error, %Juicy.Error{position: 13, line: 2, column: 4}}] =
+      Juicy.parse_stream(chunks, spec) |> Enum.into([])
+  end
+
+  test "json parsing with tag_array spec" do
+    spec = {:array, [], {:tag_array, [
+      mappings: %{
+        "point" => {:tuple, [as_tuple: true], [{:any, []}, {:any, []}]},
+        "color" => {:enum, [values: ["red", "blue"]]},
+      },
+    ]}}
+    input = ~s([["point", [1, 2]], ["color", "red"]])
+    assert Juicy.parse_spec(input, spec) == {:ok, [["point", {1, 2}], ["color", :red]]}
+
+    assert err(Juicy.parse_spec(~s([["size", 1]]), spec)) == {:unknown_tag, [0], "size"}
+    assert err(Juicy.parse_spec(~s([[1, 1]]), spec)) == {:missing_tag, [0], nil}
+    assert err(Juicy.parse_spec(~s([["color"]]), spec)) == {:tuple_length, [0], {2, 1}}
+    # The tag only selects the node for the array it is in.
+    assert err(Juicy.parse_spec(~s([["color"], ["point", [1, 2]]]), spec)) ==
+      {:tuple_length, [0], {2, 1}}
+    assert Juicy.parse_spec(~s([["point", ["color", "x"]], ["color", "red"]]), spec) ==
+      {:ok, [["point", {"color", "x"}], ["color", :red]]}
+
+    spec = {:tag_array, [as_tuple: true, mappings: %{"a" => {:static, [value: :a]}}, default: :ignore]}
+    assert Juicy.parse_spec(~s(["a", [1]]), spec) == {:ok, {"a", :a}}
+    assert Juicy.parse_spec(~s(["b", [1]]), spec) == {:ok, {"b", [1]}}
+  end
+
+  test "json parsing with type spec" do
+    spec = {:array, [], {:type, [type_mappings: %{
+      string: {:enum, [values: ["none"]]},
+      null: {:static, [value: :none]},
+      map: {:map, [atom_keys: [:a]], {:any, []}},
+    }]}}
+    assert Juicy.parse_spec(~s(["none", null, {"a": 1}, 2]), spec) ==
+      {:ok, [:none, :none, %{a: 1}, 2]}
+
+    spec = {:type, [type_mappings: %{array: {:tuple, [as_tuple: true], [{:any, []}]}}]}
+    assert Juicy.parse_spec(~s([1]), spec) == {:ok, {1}}
+    assert Juicy.parse_spec(~s(1), spec) == {:ok, 1}
+
+    spec = {:type, [type_mappings: %{map: {:type, [type_mappings: %{map: {:map, [atom_keys: [:a]], {:any, []}}}]}}]}
+    assert Juicy.parse_spec(~s({"a": 1}), spec) == {:ok, %{a: 1}}
+  end
+
+  test "json parsing with strict spec" do
+    spec = {:map_keys, [strict: true], %{
+      "a" => {:array, [], {:map, [], {:any, []}}},
+      "b" => {:type, [type_mappings: %{string: {:any, []}, null: {:any, []}}]},
+    }}
+    assert Juicy.parse_spec(~s({"a": [{}], "b": null}), spec) == {:ok, %{"a" => [%{}], "b" => nil}}
+
+    assert err(Juicy.parse_spec(~s({"a": {}, "b": null}), spec)) ==
+      {:spec_mismatch, ["a"], {:array, :map}}
+    assert err(Juicy.parse_spec(~s({"a": [{}, []], "b": null}), spec)) ==
+      {:spec_mismatch, ["a", 1], {:map, :array}}
+    assert err(Juicy.parse_spec(~s({"a": [], "b": 1}), spec)) ==
+      {:spec_mismatch, ["b"], {[:string, :null], :number}}
+    assert err(Juicy.parse_spec(~s({"a": [], "b": null, "c": 1}), spec)) ==
+      {:spec_mismatch, [], {{:keys, ["a", "b"]}, {:key, "c"}}}
+    # Keys are only required when listed.
+    assert Juicy.parse_spec(~s({"a": []}), spec) == {:ok, %{"a" => []}}
+
+    spec = {:array, [strict: true], {:any, []}}
+    assert err(Juicy.parse_spec(~s({}), spec)) == {:spec_mismatch, [], {:array, :map}}
+
+    # The nodes matchers map to are strict when the matcher is.
+    spec = {:array, [], {:tagged_map, [strict: true, key_name: "t", mappings: %{
+      "a" => {:map_keys, [], %{"t" => {:any, []}, "x" => {:array, [], {:any, []}}}},
+    }]}}
+    assert Juicy.parse_spec(~s([{"t": "a", "x": []}]), spec) == {:ok, [%{"t" => "a", "x" => []}]}
+    assert err(Juicy.parse_spec(~s([{"t": "a", "x": 1}]), spec)) ==
+      {:spec_mismatch, [0, "x"], {:array, :number}}
+
+    spec = {:map, [], {:type, [strict: true, type_mappings: %{map: {:map_keys, [], %{"x" => {:any, []}}}}]}}
+    assert err(Juicy.parse_spec(~s({"a": {"y": 1}}), spec)) ==
+      {:spec_mismatch, ["a"], {{:keys, ["x"]}, {:key, "y"}}}
+  end
+
+  test "json parsing with required keys and defaults" do
+    spec = {:map, [struct_atom: JuicyTest.TestStruct, atom_keys: [:some, :thing],
+                   required: ["some"], defaults: %{"thing" => :absent}], {:any, []}}
+    assert Juicy.parse_spec(~s({"some": 1, "thing": null}), spec) ==
+      {:ok, %JuicyTest.TestStruct{some: 1, thing: nil}}
+    assert Juicy.parse_spec(~s({"some": 1}), spec) ==
+      {:ok, %JuicyTest.TestStruct{some: 1, thing: :absent}}
+    assert err(Juicy.parse_spec(~s({"thing": 1}), spec)) ==
+      {:spec_mismatch, [], {{:key, "some"}, :missing}}
+
+    spec = {:map_keys, [strict: true, required: ["a"], defaults: %{"b" => 0}],
+            %{"a" => {:any, []}, "b" => {:any, []}}}
+    assert Juicy.parse_spec(~s({"a": 1}), spec) == {:ok, %{"a" => 1, "b" => 0}}
+    assert err(Juicy.parse_spec(~s({"b": 1}), spec)) ==
+      {:spec_mismatch, [], {{:key, "a"}, :missing}}
+  end
+
+  test "json parsing with string length constraints" do
+    spec = {:array, [], {:any, [min_length: 2, max_length: 3]}}
+    assert Juicy.parse_spec(~s(["ab", "åäö", 1]), spec) == {:ok, ["ab", "åäö", 1]}
+    assert err(Juicy.parse_spec(~s(["ab", "abcd"]), spec)) == {:string_too_long, [1], 3}
+    assert Juicy.parse_spec(~s(["a\\u00e5"]), spec) == {:ok, ["aå"]}
+    assert err(Juicy.parse_spec(~s(["a"]), spec)) == {:string_too_short, [0], {2, 1}}
+
+    spec = {:map, [], {:any, [max_length: 3, length_unit: :bytes]}}
+    assert Juicy.parse_spec(~s({"a": "abc"}), spec) == {:ok, %{"a" => "abc"}}
+    assert err(Juicy.parse_spec(~s({"a": "åä"}), spec)) == {:string_too_long, ["a"], 3}
+  end
+
+  test "json parsing with number constraints" do
+    spec = {:map, [], {:any, [min: 1, max: 2.5]}}
+    assert Juicy.parse_spec(~s({"a": 1, "b": 2.5, "c": "x"}), spec) == {:ok, %{"a" => 1, "b" => 2.5, "c" => "x"}}
+    assert err(Juicy.parse_spec(~s({"a": 0.5}), spec)) == {:invalid_number, ["a"], {:min, 1}}
+    assert err(Juicy.parse_spec(~s({"a": 3}), spec)) == {:invalid_number, ["a"], {:max, 2.5}}
+
+    spec = {:array, [], {:any, [integer_only: true, non_negative: true]}}
+    assert Juicy.parse_spec(~s([0, 10]), spec) == {:ok, [0, 10]}
+    assert err(Juicy.parse_spec(~s([0, 1.0]), spec)) == {:invalid_number, [1], :not_integer}
+    assert err(Juicy.parse_spec(~s([0, 1e2]), spec)) == {:invalid_number, [1], :not_integer}
+    assert err(Juicy.parse_spec(~s([-1]), spec)) == {:invalid_number, [0], :negative}
+
+    # Integers are compared exactly with integer bounds, which can be of
+    # any size.
+    spec = {:array, [], {:any, [min: -9007199254740992, max: 9007199254740992]}}
+    assert Juicy.parse_spec(~s([9007199254740992, -9007199254740992]), spec) ==
+      {:ok, [9007199254740992, -9007199254740992]}
+    assert err(Juicy.parse_spec(~s([9007199254740993]), spec)) ==
+      {:invalid_number, [0], {:max, 9007199254740992}}
+    assert err(Juicy.parse_spec(~s([-9007199254740993]), spec)) ==
+      {:invalid_number, [0], {:min, -9007199254740992}}
+
+    spec = {:any, [max: 100000000000000000000000000000]}
+    assert Juicy.parse_spec(~s(100000000000000000000000000000), spec) ==
+      {:ok, 100000000000000000000000000000}
+    assert err(Juicy.parse_spec(~s(100000000000000000000000000001), spec)) ==
+      {:invalid_number, [], {:max, 100000000000000000000000000000}}
+    assert err(Juicy.parse_spec(~s(1.0e30), spec)) ==
+      {:invalid_number, [], {:max, 100000000000000000000000000000}}
+  end
+
+  test "parsing with a compiled spec" do
+    {:ok, spec} = Juicy.compile_spec({:map, [atom_keys: [:a]], {:any, [stream: true]}})
+    assert Juicy.parse_spec(~s({"a": 1}), spec) == {:ok, %{a: 1}}
+    assert Juicy.parse_spec(~s({"a": 2}), spec) == {:ok, %{a: 2}}
+
+    out = Juicy.parse_stream([~s({"a": 3})], spec) |> Enum.into([])
+    assert out == [
+      {:yield, {["a"], 3}},
+      {:yield, {[], %{a: :streamed}}},
+      :finished,
+    ]
+
+    assert Juicy.compile_spec({:map, []}) == :error
+  end
+
+  test "encoding basic values" do
+    assert Juicy.encode(%{}) == {:ok, "{}"}
+    assert Juicy.encode([]) == {:ok, "[]"}
+    assert Juicy.encode([1, -1, 1.5, true, false, nil, :woo]) ==
+      {:ok, ~s([1,-1,1.5,true,false,null,"woo"])}
+    assert Juicy.encode(%{"a" => [%{b: "c"}]}) == {:ok, ~s({"a":[{"b":"c"}]})}
+    assert Juicy.encode({:a, :b}) == {:error, {:invalid_value, {:a, :b}}}
+  end
+
+  test "encoding escapes strings" do
+    assert Juicy.encode("\"\\\n\u0001å") == {:ok, ~s("\\"\\\\\\n\\u0001å")}
+    assert Juicy.encode(<<255>>) == {:error, {:invalid_string, <<255>>}}
+  end
+
+  test "pretty encoding" do
+    term = %{"a" => [1, %{}, []]}
+
+    assert Juicy.encode(term, indent: 2, colon_space: true) ==
+      {:ok, "{\n  \"a\": [\n    1,\n    {},\n    []\n  ]\n}"}
+    assert Juicy.encode(term, indent: 1, indent_with: :tabs, newline: :crlf) ==
+      {:ok, "{\r\n\t\"a\":[\r\n\t\t1,\r\n\t\t{},\r\n\t\t[]\r\n\t]\r\n}"}
+    assert Juicy.encode(term, colon_space: true) == {:ok, ~s({"a": [1,{},[]]})}
+  end
+
+  test "encoding large integers" do
+    assert Juicy.encode([9999999999999999999999999999999999999999, -99999999999999999999]) ==
+      {:ok, "[9999999999999999999999999999999999999999,-99999999999999999999]"}
+  end
+
+  test "encoding large terms roundtrips" do
+    term = for i <- 1..50_000, do: %{"index" => i, "name" => "item"}
+    {:ok, binary} = Juicy.encode(term)
+    assert Juicy.parse(binary) == {:ok, term}
+
+    term = %{"a" => Map.new(1..100_000, &{Integer.to_string(&1), [&1]})}
+    {:ok, binary} = Juicy.encode(term)
+    assert Juicy.parse(binary) == {:ok, term}
+  end
+
+  test "encoding improper lists" do
+    assert Juicy.encode([1 | 2]) == {:error, {:invalid_value, 2}}
+    assert Juicy.encode(%{"a" => [[1], 2 | :b]}) == {:error, {:invalid_value, :b}}
+  end
+
 end